@@ -1 +1,510 @@
-pub mod gradle;
\ No newline at end of file
+pub mod gradle;
+pub mod manifest;
+pub mod maven;
+pub mod metadata;
+pub mod verify;
+
+use crate::building::gradle::GradleBuilder;
+use crate::building::manifest::{JarManifest, ManifestError, read_jar_manifest};
+use crate::building::maven::MavenBuilder;
+use crate::building::metadata::{BuildMetadata, BuildMetadataStore};
+use crate::config::{ArtifactPattern, BuilderKind, ComponentConfig, GradleSettings};
+use crate::java::JdkTrait;
+use crate::utils::download::manager::DownloadManager;
+use crate::utils::process::{kill_process_tree, spawn_process_group};
+use crate::utils::tempfile_async::TempFile;
+use crate::utils::timestamp::current_unix_timestamp_in_ms;
+use anyhow::Context;
+use log::{info, warn};
+use reqwest::Client;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::fs;
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::ReadDirStream;
+use tokio_util::sync::CancellationToken;
+
+/// Shared state needed by every build
+pub struct BuildContext<'a, J: JdkTrait> {
+    pub client: &'a Client,
+    /// Fetches distributions and other artifacts through the configured mirrors
+    pub downloads: &'a DownloadManager,
+    /// The bootstrap base directory, used for caches and metadata
+    pub data_dir: &'a Path,
+    pub jdk: &'a J,
+    pub gradle_settings: &'a GradleSettings,
+    /// Builds running longer than this are killed
+    pub timeout: Option<Duration>,
+    /// Never restore task outputs from a build cache, so the build proves it is reproducible
+    pub no_build_cache: bool,
+    /// Cancelled on Ctrl-C, running builds are stopped and no new ones start
+    pub cancel: &'a CancellationToken,
+}
+
+#[derive(Error, Debug)]
+pub enum BuildError {
+    #[error("{tool} build failed with exit code {code}")]
+    Failed { tool: &'static str, code: i32 },
+
+    #[error("{tool} build timed out after {}s", timeout.as_secs())]
+    TimedOut {
+        tool: &'static str,
+        timeout: Duration,
+    },
+
+    #[error("{tool} build was cancelled")]
+    Cancelled { tool: &'static str },
+
+    #[error("IO Error")]
+    Io(#[from] std::io::Error),
+}
+
+/// A bootstrapped component: where its source comes from and where its jar goes
+pub struct Component<'a> {
+    pub name: &'static str,
+    pub repository: &'static str,
+    pub branch: &'a str,
+    pub repo_path: PathBuf,
+    pub jar_path: PathBuf,
+    pub kind: ArtifactKind,
+    pub config: &'a ComponentConfig,
+}
+
+/// How a component's jar is used, which decides the manifest attributes it needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArtifactKind {
+    /// Launched with `java -jar`
+    Application,
+    /// Loaded with `-javaagent` or attached at runtime
+    JavaAgent,
+}
+
+impl ArtifactKind {
+    /// Any one of these attributes must be present
+    pub fn required_attributes(&self) -> &'static [&'static str] {
+        match self {
+            ArtifactKind::Application => &["Main-Class"],
+            ArtifactKind::JavaAgent => &["Premain-Class", "Agent-Class"],
+        }
+    }
+}
+
+/// Check that a built jar is readable and usable as `kind`
+pub async fn validate_artifact(
+    jar: &Path,
+    kind: ArtifactKind,
+) -> Result<JarManifest, ManifestError> {
+    let manifest = read_jar_manifest(jar).await?;
+    let attributes = kind.required_attributes();
+    if !attributes.iter().any(|name| manifest.get(name).is_some()) {
+        return Err(ManifestError::MissingAttribute {
+            jar: Box::new(jar.to_owned()),
+            attributes,
+        });
+    }
+    Ok(manifest)
+}
+
+/// A build tool which turns a project checkout into a jar
+pub trait Builder {
+    /// Human readable name of the build tool
+    fn name(&self) -> &'static str;
+
+    /// Build the project and return the path of the emitted artifact
+    async fn build(
+        &self,
+        context: &BuildContext<'_, impl JdkTrait>,
+        project_path: &Path,
+    ) -> anyhow::Result<PathBuf>;
+}
+
+/// Detect the build tool of a project from its build files.
+/// Gradle wins if a project contains both.
+pub async fn detect_builder(project_path: &Path) -> anyhow::Result<Option<BuilderKind>> {
+    for file in [
+        "build.gradle",
+        "build.gradle.kts",
+        "settings.gradle",
+        "settings.gradle.kts",
+    ] {
+        if fs::try_exists(project_path.join(file)).await? {
+            return Ok(Some(BuilderKind::Gradle));
+        }
+    }
+    if fs::try_exists(project_path.join("pom.xml")).await? {
+        return Ok(Some(BuilderKind::Maven));
+    }
+    Ok(None)
+}
+
+/// Build a component with its configured (or detected) build tool,
+/// validate the artifact, then install it to the component's jar path
+pub async fn build_component(
+    context: &BuildContext<'_, impl JdkTrait>,
+    component: &Component<'_>,
+    commit: &str,
+) -> anyhow::Result<()> {
+    // never replace a working jar with a broken one
+    let (artifact, manifest) = build_artifact(context, component, &component.repo_path).await?;
+    install_artifact(&artifact, &component.jar_path).await?;
+
+    // the new jar is in place, losing its metadata does not make the build fail
+    if let Err(err) = BuildMetadataStore::record(
+        &context.data_dir.join("build-metadata.json"),
+        component.name,
+        BuildMetadata {
+            commit: commit.to_string(),
+            implementation_version: manifest.get("Implementation-Version").map(String::from),
+            built_at: current_unix_timestamp_in_ms(),
+        },
+    )
+    .await
+    {
+        warn!(
+            "Failed to record build metadata of {}: {err:#}",
+            component.name
+        );
+    }
+    Ok(())
+}
+
+/// Build a checkout of a component and validate the emitted jar, without installing it
+pub async fn build_artifact(
+    context: &BuildContext<'_, impl JdkTrait>,
+    component: &Component<'_>,
+    project_path: &Path,
+) -> anyhow::Result<(PathBuf, JarManifest)> {
+    let config = component.config;
+    let kind = match config.builder {
+        BuilderKind::Auto => detect_builder(project_path).await?.ok_or_else(|| {
+            anyhow::anyhow!("Cannot detect the build tool of {}", project_path.display())
+        })?,
+        kind => kind,
+    };
+
+    let artifact = match kind {
+        BuilderKind::Maven => {
+            let builder = MavenBuilder {
+                config: &config.maven,
+            };
+            info!("Building {} with {}", component.name, builder.name());
+            builder.build(context, project_path).await?
+        }
+        BuilderKind::Gradle | BuilderKind::Auto => {
+            let builder = GradleBuilder {
+                config: &config.gradle,
+            };
+            info!("Building {} with {}", component.name, builder.name());
+            builder.build(context, project_path).await?
+        }
+    };
+
+    let manifest = validate_artifact(&artifact, component.kind).await?;
+    Ok((artifact, manifest))
+}
+
+/// Run a build command to completion, failing on a non-zero exit status.
+///
+/// The build runs in its own process group. If it exceeds `timeout` or `cancel` is
+/// cancelled, the whole process tree (including the processes it forked) is killed.
+/// Nothing is started if `cancel` is already cancelled.
+pub async fn run_build_command(
+    tool: &'static str,
+    command: &mut tokio::process::Command,
+    timeout: Option<Duration>,
+    cancel: &CancellationToken,
+) -> Result<(), BuildError> {
+    if cancel.is_cancelled() {
+        return Err(BuildError::Cancelled { tool });
+    }
+    let mut child = spawn_process_group(command)?;
+
+    let deadline = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    // wait for build thread
+    let error = tokio::select! {
+        status = child.wait() => {
+            let status = status?;
+            if !status.success() {
+                return Err(BuildError::Failed {
+                    tool,
+                    code: status.code().unwrap_or(-1),
+                });
+            }
+            info!("{tool} built successfully");
+            return Ok(());
+        }
+        _ = deadline => BuildError::TimedOut {
+            tool,
+            timeout: timeout.unwrap_or_default(),
+        },
+        _ = cancel.cancelled() => BuildError::Cancelled { tool },
+    };
+
+    warn!("{error}, stopping the build processes");
+    kill_process_tree(&mut child).await?;
+    Err(error)
+}
+
+/// Locate the first file in `dir` matching `pattern`, skipping names rejected by `skip`
+pub async fn find_artifact(
+    dir: &Path,
+    pattern: &ArtifactPattern,
+    skip: impl Fn(&str) -> bool,
+) -> anyhow::Result<PathBuf> {
+    let mut stream = ReadDirStream::new(fs::read_dir(dir).await?);
+    while let Some(file) = stream.next().await {
+        let file = file?;
+
+        let file_name = file.file_name();
+        let file_name: String = file_name.to_string_lossy().into();
+        if !skip(&file_name) && pattern.matches(&file_name) {
+            return Ok(file.path());
+        }
+    }
+    anyhow::bail!("No artifact matching {pattern} in {}", dir.display())
+}
+
+/// Install a built jar to its final location.
+///
+/// The jar is synced and renamed over the destination, so the previous jar stays in
+/// place until the new one is complete. If the build directory lives on another
+/// filesystem, the jar is copied into a temp file next to the destination first.
+pub async fn install_artifact(built_jar: &Path, emitted_jar_path: &Path) -> anyhow::Result<()> {
+    let parent = emitted_jar_path.parent().unwrap();
+    fs::create_dir_all(parent).await?;
+
+    info!(
+        "Install built jar {} to {}",
+        built_jar.display(),
+        emitted_jar_path.display()
+    );
+    // on failure the previous jar is untouched, and the temp file removes itself
+    move_synced(built_jar, emitted_jar_path)
+        .await
+        .with_context(|| format!("Failed to install {}", emitted_jar_path.display()))?;
+    sync_dir(parent).await;
+
+    info!("Successful built {}", emitted_jar_path.display());
+    Ok(())
+}
+
+/// Move `source` to `dest` atomically, making sure its content reached the disk
+async fn move_synced(source: &Path, dest: &Path) -> io::Result<()> {
+    // flushing needs write access on Windows
+    fs::OpenOptions::new()
+        .write(true)
+        .open(source)
+        .await?
+        .sync_all()
+        .await?;
+    match fs::rename(source, dest).await {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            let mut staged = TempFile::new_in(dest.parent().unwrap()).await?;
+            tokio::io::copy(&mut fs::File::open(source).await?, &mut *staged).await?;
+            staged.sync_all().await?;
+            staged.persist(dest).await
+        }
+        result => result,
+    }
+}
+
+/// Persist a rename by syncing the directory entry, best-effort
+async fn sync_dir(dir: &Path) {
+    #[cfg(unix)]
+    if let Ok(dir) = fs::File::open(dir).await {
+        let _ = dir.sync_all().await;
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_validate_artifact_requires_agent_attributes() {
+        use async_zip::tokio::write::ZipFileWriter;
+        use async_zip::{Compression, ZipEntryBuilder};
+
+        let dir = tempfile::tempdir().unwrap();
+        let jar = dir.path().join("app.jar");
+        let mut writer = ZipFileWriter::with_tokio(fs::File::create(&jar).await.unwrap());
+        let entry = ZipEntryBuilder::new("META-INF/MANIFEST.MF".into(), Compression::Deflate);
+        writer
+            .write_entry_whole(entry, b"Manifest-Version: 1.0\r\nMain-Class: Main\r\n")
+            .await
+            .unwrap();
+        writer.close().await.unwrap();
+
+        assert!(
+            validate_artifact(&jar, ArtifactKind::Application)
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            validate_artifact(&jar, ArtifactKind::JavaAgent).await,
+            Err(ManifestError::MissingAttribute { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_install_artifact_replaces_previous_jar() {
+        let dir = tempfile::tempdir().unwrap();
+        let built_jar = dir.path().join("build").join("app-fatjar.jar");
+        let emitted_jar = dir.path().join("install").join("app.jar");
+        fs::create_dir_all(built_jar.parent().unwrap())
+            .await
+            .unwrap();
+        fs::create_dir_all(emitted_jar.parent().unwrap())
+            .await
+            .unwrap();
+        fs::write(&built_jar, b"new").await.unwrap();
+        fs::write(&emitted_jar, b"old").await.unwrap();
+
+        install_artifact(&built_jar, &emitted_jar).await.unwrap();
+
+        assert_eq!(fs::read(&emitted_jar).await.unwrap(), b"new");
+        assert!(!fs::try_exists(&built_jar).await.unwrap());
+        let leftovers: Vec<_> = std::fs::read_dir(emitted_jar.parent().unwrap())
+            .unwrap()
+            .collect();
+        assert_eq!(leftovers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_install_artifact_keeps_previous_jar_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let emitted_jar = dir.path().join("app.jar");
+        fs::write(&emitted_jar, b"old").await.unwrap();
+
+        let result = install_artifact(&dir.path().join("missing.jar"), &emitted_jar).await;
+
+        assert!(result.is_err());
+        assert_eq!(fs::read(&emitted_jar).await.unwrap(), b"old");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_build_command_timeout_kills_process_tree() {
+        let mut command = tokio::process::Command::new("sh");
+        command.args(["-c", "sleep 30 & sleep 30"]);
+
+        let started = std::time::Instant::now();
+        let result = run_build_command(
+            "Test",
+            &mut command,
+            Some(Duration::from_millis(200)),
+            &CancellationToken::new(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(BuildError::TimedOut { .. })));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_json_progress_stays_clean_with_chatty_build() {
+        use crate::utils::download::progress::{
+            DownloadEvent, JsonEventStream, ProgressSubscriber,
+        };
+        use crate::utils::process::reserve_stdout;
+
+        // stdout of the test process is checked, so the scenario runs in a child process
+        const CHILD_ENV: &str = "CELESTIAL_TEST_CHATTY_BUILD";
+        if std::env::var_os(CHILD_ENV).is_some() {
+            reserve_stdout();
+            let stream = JsonEventStream::new(io::stdout());
+            let url = "https://example.com/a".to_string();
+            stream.on_event(&DownloadEvent::Started {
+                id: 1,
+                url: url.clone(),
+                dest: PathBuf::from("a"),
+                total: None,
+                chunks: 1,
+            });
+            let mut command = tokio::process::Command::new("sh");
+            command.args(["-c", "echo build noise; echo more build noise"]);
+            run_build_command("Test", &mut command, None, &CancellationToken::new())
+                .await
+                .unwrap();
+            stream.on_event(&DownloadEvent::Finished {
+                id: 1,
+                url,
+                size: 1,
+            });
+            return;
+        }
+
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "building::tests::test_json_progress_stays_clean_with_chatty_build",
+            ])
+            .env(CHILD_ENV, "1")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(!stdout.contains("build noise"));
+        let events: Vec<serde_json::Value> = stdout
+            .lines()
+            // the first event follows the test name printed by the harness
+            .filter_map(|line| line.find('{').map(|start| &line[start..]))
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("build noise"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_build_command_failure() {
+        let mut command = tokio::process::Command::new("sh");
+        command.args(["-c", "exit 3"]);
+
+        let result = run_build_command("Test", &mut command, None, &CancellationToken::new()).await;
+        assert!(matches!(result, Err(BuildError::Failed { code: 3, .. })));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_build_command_cancel() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("started");
+        let mut command = tokio::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("touch {}; sleep 30", marker.display()));
+
+        // cancelled before it starts, nothing runs
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let result = run_build_command("Test", &mut command, None, &cancel).await;
+        assert!(matches!(result, Err(BuildError::Cancelled { .. })));
+        assert!(!marker.exists());
+
+        let cancel = CancellationToken::new();
+        let started = std::time::Instant::now();
+        let (result, ()) = tokio::join!(
+            run_build_command("Test", &mut command, None, &cancel),
+            async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                cancel.cancel();
+            }
+        );
+        assert!(matches!(result, Err(BuildError::Cancelled { .. })));
+        assert!(marker.exists());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
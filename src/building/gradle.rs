@@ -1,12 +1,28 @@
-use crate::java::JdkTrait;
-use log::info;
+pub mod cache;
+pub mod native;
+pub mod validation;
+pub mod wrapper;
+
+use crate::building::gradle::cache::{
+    http_cache_dir, isolated_user_home, write_build_cache_init_script,
+};
+use crate::building::gradle::native::resolve_native_gradle;
+use crate::building::gradle::validation::{
+    GRADLE_SERVICES_URL, WrapperValidation, WrapperValidator,
+};
+use crate::building::gradle::wrapper::{WrapperProperties, wrapper_jar_path};
+use crate::building::{BuildContext, Builder, find_artifact, run_build_command};
+use crate::config::{GradleConfig, GradleSettings};
+use crate::java::{JdkTrait, java_executable_in};
+use crate::utils::download::conditional::ConditionalCache;
+use log::{info, warn};
+use std::collections::BTreeMap;
 use std::env;
 use std::error::Error as StdError;
 use std::fmt;
+use std::io;
 use std::path::{Path, PathBuf};
 use tokio::fs;
-use tokio_stream::StreamExt;
-use tokio_stream::wrappers::ReadDirStream;
 
 /// Represents errors that can occur during Gradle argument generation.
 #[derive(Debug)]
@@ -14,6 +30,9 @@ pub enum GenerateArgsError {
     /// `JAVA_HOME` was not provided, and the `java` executable could not be found
     /// in the system's `PATH`.
     JavaNotFound,
+
+    /// `JAVA_HOME` was provided, but it does not contain a `java` executable.
+    InvalidJavaHome(PathBuf),
 }
 
 impl fmt::Display for GenerateArgsError {
@@ -25,6 +44,13 @@ impl fmt::Display for GenerateArgsError {
                  Please set the JAVA_HOME variable in your environment to match the \
                  location of your Java installation."
             ),
+            GenerateArgsError::InvalidJavaHome(java_home) => write!(
+                f,
+                "ERROR: JAVA_HOME is set to an invalid directory: {}\n\
+                 Please set the JAVA_HOME variable in your environment to match the \
+                 location of your Java installation.",
+                java_home.display()
+            ),
         }
     }
 }
@@ -51,6 +77,15 @@ pub struct GradleLaunchOptions<'a> {
     /// A slice of command-line arguments that were passed to the script.
     pub cli_args: &'a [String],
 
+    /// The Gradle tasks to execute, appended after all other arguments.
+    pub tasks: &'a [String],
+
+    /// Project properties, passed to Gradle as `-Pkey=value`.
+    pub project_properties: &'a BTreeMap<String, String>,
+
+    /// System properties, passed to Gradle as `-Dkey=value`.
+    pub system_properties: &'a BTreeMap<String, String>,
+
     /// An optional override for the `GRADLE_OPTS` environment variable.
     /// If `None`, the function will attempt to read it from the environment.
     pub gradle_opts: Option<&'a str>,
@@ -98,20 +133,22 @@ pub fn generate_gradle_args(
     // Determine the Java command to use to start the JVM.
     // This logic mimics the script's handling of the `JAVA_HOME` environment variable.
     let java_cmd = match options.jdk_home {
-        Some(java_home) => java_home,
+        Some(java_home) => {
+            let java_cmd = java_executable_in(java_home);
+            if !java_cmd.is_file() {
+                return Err(GenerateArgsError::InvalidJavaHome(java_home.to_owned()));
+            }
+            java_cmd
+        }
         None => {
             // If JAVA_HOME is not set, search for `java` in the system's PATH.
-            &which::which("java").map_err(|_| GenerateArgsError::JavaNotFound)?
+            which::which("java").map_err(|_| GenerateArgsError::JavaNotFound)?
         }
     };
 
     // Define constants and derived paths as in the script.
     const DEFAULT_JVM_OPTS: &str = r#""-Xmx64m" "-Xms64m""#;
-    let classpath = options
-        .app_home
-        .join("gradle")
-        .join("wrapper")
-        .join("gradle-wrapper.jar");
+    let classpath = wrapper_jar_path(options.app_home);
 
     // Get JVM options from the environment or the provided override options.
     // An empty string is used as a safe default if the environment variable is not set.
@@ -128,7 +165,7 @@ pub fn generate_gradle_args(
     // word-splitting on the options string while respecting quotes.
     // The `shlex::split` function is the idiomatic and safe Rust equivalent.
     let all_jvm_opts_str = format!("{} {} {}", DEFAULT_JVM_OPTS, java_opts, gradle_opts);
-    let jvm_opts = shlex::split(&all_jvm_opts_str).unwrap_or_else(Vec::new);
+    let jvm_opts = shlex::split(&all_jvm_opts_str).unwrap_or_default();
 
     // Collect all arguments for the `java` command in the correct order.
     let mut final_args: Vec<String> = Vec::new();
@@ -146,75 +183,329 @@ pub fn generate_gradle_args(
     // 4. Add the main class to run.
     final_args.push("org.gradle.wrapper.GradleWrapperMain".to_string());
 
-    // 5. Add all original command-line arguments passed to the script.
-    final_args.extend_from_slice(options.cli_args);
+    // 5. Add the arguments for Gradle itself.
+    final_args.extend(gradle_cli_args(options));
+
+    Ok((java_cmd, final_args))
+}
+
+/// Generates the command and arguments to run a native Gradle installation.
+///
+/// Unlike [generate_gradle_args], the JVM is started by the `gradle` launcher script,
+/// so `GRADLE_OPTS` and `JAVA_OPTS` must be passed through the environment by the caller.
+pub fn generate_native_gradle_args(
+    options: &GradleLaunchOptions,
+    gradle_executable: &Path,
+) -> (PathBuf, Vec<String>) {
+    (gradle_executable.to_owned(), gradle_cli_args(options))
+}
+
+/// The arguments passed to Gradle itself, shared by the wrapper and native launchers.
+fn gradle_cli_args(options: &GradleLaunchOptions) -> Vec<String> {
+    // 1. Add all original command-line arguments passed to the script.
+    let mut args = options.cli_args.to_vec();
+
+    // 2. Pin the Gradle daemon and toolchain resolution to the same JDK,
+    //    user supplied properties come later so they can still override these.
+    if let Some(java_home) = options.jdk_home {
+        let java_home = java_home.to_string_lossy();
+        args.push(format!("-Dorg.gradle.java.home={java_home}"));
+        args.push(format!("-Porg.gradle.java.installations.paths={java_home}"));
+        args.push("-Porg.gradle.java.installations.auto-detect=false".to_string());
+    }
+
+    // 3. Add project and system properties, then the tasks to run.
+    args.extend(
+        options
+            .project_properties
+            .iter()
+            .map(|(key, value)| format!("-P{key}={value}")),
+    );
+    args.extend(
+        options
+            .system_properties
+            .iter()
+            .map(|(key, value)| format!("-D{key}={value}")),
+    );
+    args.extend_from_slice(options.tasks);
+    args
+}
+
+/// Resolve the Gradle user home used for builds.
+///
+/// This is [isolated_user_home] if enabled, otherwise the user's own one,
+/// honoring the `GRADLE_USER_HOME` environment variable.
+pub fn gradle_user_home(data_dir: &Path, settings: &GradleSettings) -> PathBuf {
+    if settings.isolated_user_home {
+        return isolated_user_home(data_dir);
+    }
+    env::var_os("GRADLE_USER_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| env::home_dir().unwrap().join(".gradle"))
+}
+
+/// Builds projects with the Gradle wrapper, or a native Gradle if there is none
+pub struct GradleBuilder<'a> {
+    pub config: &'a GradleConfig,
+}
 
-    Ok((PathBuf::from(java_cmd), final_args))
+impl GradleBuilder<'_> {
+    /// The daemon and build cache options followed by the configured arguments
+    async fn cli_args(&self, context: &BuildContext<'_, impl JdkTrait>) -> io::Result<Vec<String>> {
+        // a daemon outlives the build and detaches from its process group,
+        // so a timed out or cancelled build could not take it down
+        let mut cli_args = vec!["--no-daemon".to_string()];
+        cli_args.extend_from_slice(&self.config.args);
+        if context.no_build_cache {
+            // also overrides `org.gradle.caching` of the project
+            cli_args.push("--no-build-cache".to_string());
+        } else if context.gradle_settings.local_build_cache {
+            let init_script = write_build_cache_init_script(context.data_dir).await?;
+            cli_args.push("--build-cache".to_string());
+            cli_args.push("--init-script".to_string());
+            cli_args.push(init_script.to_string_lossy().into_owned());
+        }
+        Ok(cli_args)
+    }
 }
 
-pub async fn build_with_gradle(
-    jdk: &impl JdkTrait,
-    project_path: &Path,
-    emitted_jar_path: &Path,
-    fatjar_pattern: &str,
-) -> anyhow::Result<()> {
-    let gradle_run_cmd = generate_gradle_args(&GradleLaunchOptions {
-        jdk_home: Some(jdk.java_executable()),
-        app_home: project_path,
-        app_base_name: "gradlew",
-
-        cli_args: &["build".to_string()],
-        gradle_opts: None,
-        java_opts: None,
-    })?;
-
-    // do cleanup first
-    let build_libs_dir = project_path.join("build").join("libs");
-
-    if fs::try_exists(&build_libs_dir).await? {
-        info!("Clean build files: {}", build_libs_dir.display());
-        fs::remove_dir_all(&build_libs_dir).await?;
+impl Builder for GradleBuilder<'_> {
+    fn name(&self) -> &'static str {
+        "Gradle"
     }
 
-    info!("Spawning gradle: {}", gradle_run_cmd.1.join(" "));
-
-    let mut command = tokio::process::Command::new(&gradle_run_cmd.0);
-    command.args(gradle_run_cmd.1);
-    command.current_dir(&project_path);
-    let mut child = command.spawn()?;
-
-    // wait for build thread
-    child.wait().await?;
-    info!("Gradle built successfully");
-
-    // locate emitted .jar file
-    let mut stream = ReadDirStream::new(fs::read_dir(&build_libs_dir).await?);
-    while let Some(file) = stream.next().await {
-        let file = file?;
-
-        let file_name = file.file_name();
-        let file_name: String = file_name.to_string_lossy().into();
-        println!("{file_name}");
-        if file_name.contains(fatjar_pattern) {
-            if fs::try_exists(emitted_jar_path).await? {
-                // remove this file
-                info!("Remove exist jar {}", emitted_jar_path.display());
-                fs::remove_file(emitted_jar_path).await?;
+    async fn build(
+        &self,
+        context: &BuildContext<'_, impl JdkTrait>,
+        project_path: &Path,
+    ) -> anyhow::Result<PathBuf> {
+        let gradle_config = self.config;
+        let jdk = context.jdk;
+        let settings = context.gradle_settings;
+        let user_home = gradle_user_home(context.data_dir, settings);
+
+        let cli_args = self.cli_args(context).await?;
+        let launch_options = GradleLaunchOptions {
+            jdk_home: Some(jdk.java_home()),
+            app_home: project_path,
+            app_base_name: "gradlew",
+
+            cli_args: &cli_args,
+            tasks: &gradle_config.tasks,
+            project_properties: &gradle_config.project_properties,
+            system_properties: &gradle_config.system_properties,
+            gradle_opts: gradle_config.gradle_opts.as_deref(),
+            java_opts: gradle_config.java_opts.as_deref(),
+        };
+
+        let wrapper_jar = wrapper_jar_path(project_path);
+        let (gradle_run_cmd, native) = if fs::try_exists(&wrapper_jar).await? {
+            let wrapper = WrapperProperties::load(project_path).await?;
+
+            // never run a wrapper jar we cannot trace back to an official Gradle release
+            let validator = WrapperValidator {
+                client: context.client,
+                services_url: GRADLE_SERVICES_URL,
+                cache_path: context.data_dir.join("gradle-wrapper-checksums.txt"),
+                http_cache: ConditionalCache::new(http_cache_dir(context.data_dir)),
+                trusted_checksums: &settings.trusted_wrapper_checksums,
+                allow_unknown: settings.allow_unknown_wrapper,
+            };
+            match validator.validate(&wrapper_jar, wrapper.as_ref()).await? {
+                WrapperValidation::Trusted => info!("Gradle wrapper jar verified"),
+                WrapperValidation::Unknown(checksum) if settings.allow_unknown_wrapper => {
+                    warn!("Running unknown Gradle wrapper jar (sha256 {checksum}), as requested")
+                }
+                WrapperValidation::Unknown(checksum) => anyhow::bail!(
+                    "Refusing to run unknown Gradle wrapper jar {} (sha256 {checksum}), \
+                 trust it with --allow-unknown-wrapper or `trusted_wrapper_checksums`",
+                    wrapper_jar.display()
+                ),
+            }
+
+            // fetch the distribution ourselves, so mirrors can be used
+            if let Some(wrapper) = &wrapper {
+                wrapper
+                    .provision_distribution(
+                        context.downloads,
+                        project_path,
+                        &user_home,
+                        settings.distribution_mirror.as_deref(),
+                    )
+                    .await?;
             }
-            // move file
-            let built_jar = file.path();
+
+            (generate_gradle_args(&launch_options)?, false)
+        } else {
+            let gradle = resolve_native_gradle(
+                context.downloads,
+                context.data_dir,
+                settings.version.as_deref(),
+                settings.distribution_mirror.as_deref(),
+            )
+            .await?;
             info!(
-                "Move built jar {} to {}",
-                built_jar.display(),
-                emitted_jar_path.display()
+                "No Gradle wrapper found in {}, using {gradle}",
+                project_path.display()
             );
-            let parent = emitted_jar_path.parent().unwrap();
-            fs::create_dir_all(parent).await?;
-            fs::rename(built_jar, emitted_jar_path).await?;
-            info!("Successful built {}", emitted_jar_path.display());
-            break;
+            (
+                generate_native_gradle_args(&launch_options, gradle.executable()),
+                true,
+            )
+        };
+
+        // do cleanup first
+        let build_libs_dir = project_path.join("build").join("libs");
+
+        if fs::try_exists(&build_libs_dir).await? {
+            info!("Clean build files: {}", build_libs_dir.display());
+            fs::remove_dir_all(&build_libs_dir).await?;
+        }
+
+        info!("Spawning gradle: {}", gradle_run_cmd.1.join(" "));
+
+        let mut command = tokio::process::Command::new(&gradle_run_cmd.0);
+        command.args(gradle_run_cmd.1);
+        command.current_dir(project_path);
+        command.env("JAVA_HOME", jdk.java_home());
+        if settings.isolated_user_home {
+            // keeps user-wide init scripts and gradle.properties out of the build
+            command.env("GRADLE_USER_HOME", &user_home);
+        }
+        if native {
+            // the launcher script reads these itself
+            if let Some(gradle_opts) = launch_options.gradle_opts {
+                command.env("GRADLE_OPTS", gradle_opts);
+            }
+            if let Some(java_opts) = launch_options.java_opts {
+                command.env("JAVA_OPTS", java_opts);
+            }
+        }
+        run_build_command("Gradle", &mut command, context.timeout, context.cancel).await?;
+
+        // locate emitted .jar file
+        find_artifact(&build_libs_dir, &gradle_config.artifact, |_| false).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::download::manager::DownloadManager;
+    use crate::utils::download::test_server::test_client;
+    use tokio_util::sync::CancellationToken;
+
+    struct FakeJdk;
+
+    impl JdkTrait for FakeJdk {
+        fn java_home(&self) -> &Path {
+            Path::new("/jdk")
+        }
+
+        fn java_executable(&self) -> &Path {
+            Path::new("/jdk/bin/java")
+        }
+
+        fn version(&self) -> i32 {
+            21
         }
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_cli_args_build_cache() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let downloads = DownloadManager::new(test_client(), &BTreeMap::new());
+        let settings = GradleSettings {
+            local_build_cache: true,
+            ..GradleSettings::default()
+        };
+        let config = GradleConfig {
+            args: vec!["--parallel".to_string()],
+            ..GradleConfig::default()
+        };
+        let builder = GradleBuilder { config: &config };
+        let mut context = BuildContext {
+            client: downloads.client(),
+            downloads: &downloads,
+            data_dir: data_dir.path(),
+            jdk: &FakeJdk,
+            gradle_settings: &settings,
+            timeout: None,
+            no_build_cache: false,
+            cancel: &CancellationToken::new(),
+        };
+
+        let args = builder.cli_args(&context).await.unwrap();
+        assert_eq!(args[..3], ["--no-daemon", "--parallel", "--build-cache"]);
+        assert_eq!(args[3], "--init-script");
+
+        // e.g. verify-build must not restore the outputs of an earlier build
+        context.no_build_cache = true;
+        let args = builder.cli_args(&context).await.unwrap();
+        assert_eq!(args, ["--no-daemon", "--parallel", "--no-build-cache"]);
+    }
+
+    #[test]
+    fn test_generate_gradle_args_invalid_java_home() {
+        let java_home = tempfile::tempdir().unwrap();
+        let result = generate_gradle_args(&GradleLaunchOptions {
+            jdk_home: Some(java_home.path()),
+            app_home: Path::new("/project"),
+            app_base_name: "gradlew",
+            cli_args: &[],
+            tasks: &[],
+            project_properties: &BTreeMap::new(),
+            system_properties: &BTreeMap::new(),
+            gradle_opts: Some(""),
+            java_opts: Some(""),
+        });
+        assert!(matches!(result, Err(GenerateArgsError::InvalidJavaHome(_))));
+    }
+
+    #[test]
+    fn test_generate_gradle_args_with_config() {
+        let java_home = tempfile::tempdir().unwrap();
+        let java_executable = java_executable_in(java_home.path());
+        std::fs::create_dir_all(java_executable.parent().unwrap()).unwrap();
+        std::fs::write(&java_executable, b"").unwrap();
+
+        let project_properties = BTreeMap::from([("version".to_string(), "1.0".to_string())]);
+        let system_properties =
+            BTreeMap::from([("file.encoding".to_string(), "UTF-8".to_string())]);
+        let (java, args) = generate_gradle_args(&GradleLaunchOptions {
+            jdk_home: Some(java_home.path()),
+            app_home: Path::new("/project"),
+            app_base_name: "gradlew",
+            cli_args: &["-x".to_string(), "test".to_string()],
+            tasks: &["shadowJar".to_string()],
+            project_properties: &project_properties,
+            system_properties: &system_properties,
+            gradle_opts: Some("-Xmx1g"),
+            java_opts: Some(""),
+        })
+        .unwrap();
+
+        assert_eq!(java, java_executable);
+        assert_eq!(&args[..3], ["-Xmx64m", "-Xms64m", "-Xmx1g"]);
+        let main_class = args
+            .iter()
+            .position(|arg| arg == "org.gradle.wrapper.GradleWrapperMain")
+            .unwrap();
+        assert_eq!(
+            &args[main_class + 1..],
+            [
+                "-x".to_string(),
+                "test".to_string(),
+                format!("-Dorg.gradle.java.home={}", java_home.path().display()),
+                format!(
+                    "-Porg.gradle.java.installations.paths={}",
+                    java_home.path().display()
+                ),
+                "-Porg.gradle.java.installations.auto-detect=false".to_string(),
+                "-Pversion=1.0".to_string(),
+                "-Dfile.encoding=UTF-8".to_string(),
+                "shadowJar".to_string()
+            ]
+        );
+    }
 }
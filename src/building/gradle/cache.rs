@@ -0,0 +1,259 @@
+use crate::building::gradle::gradle_user_home;
+use crate::building::gradle::native::DEFAULT_GRADLE_VERSION;
+use crate::building::gradle::wrapper::WrapperProperties;
+use crate::config::GradleSettings;
+use crate::utils::disk::{dir_size, remove_files_older_than, remove_path};
+use log::{debug, info};
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+
+const BUILD_CACHE_INIT_SCRIPT: &str = "build-cache.init.gradle";
+
+/// The Gradle user home used when `isolated_user_home` is enabled
+pub fn isolated_user_home(data_dir: &Path) -> PathBuf {
+    data_dir.join("gradle-home")
+}
+
+/// The directory of the local build cache, shared by every component
+pub fn local_build_cache_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join("build-cache")
+}
+
+/// Version lists and checksum files fetched with conditional requests
+pub fn http_cache_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join("http-cache")
+}
+
+/// Write an init script pointing the local build cache to [local_build_cache_dir].
+///
+/// The script is passed with `--init-script`, rather than put into `init.d`, so it
+/// never leaks into builds outside the bootstrap when the user home is shared.
+pub async fn write_build_cache_init_script(data_dir: &Path) -> io::Result<PathBuf> {
+    let cache_dir = local_build_cache_dir(data_dir);
+    fs::create_dir_all(&cache_dir).await?;
+    // single quoted groovy string
+    let cache_dir = cache_dir
+        .to_string_lossy()
+        .replace('\\', "\\\\")
+        .replace('\'', "\\'");
+    let script = format!(
+        "beforeSettings {{ settings ->\n    \
+             settings.buildCache {{\n        \
+                 local {{\n            \
+                     enabled = true\n            \
+                     directory = new File('{cache_dir}')\n        \
+                 }}\n    \
+             }}\n\
+         }}\n"
+    );
+    let script_path = data_dir.join(BUILD_CACHE_INIT_SCRIPT);
+    fs::write(&script_path, script).await?;
+    Ok(script_path)
+}
+
+/// Disk usage of one of the caches
+#[derive(Debug)]
+pub struct CacheUsage {
+    pub name: &'static str,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Measure the caches owned by the bootstrap.
+///
+/// A shared Gradle user home belongs to the user and is left out.
+pub async fn cache_usage(
+    data_dir: &Path,
+    settings: &GradleSettings,
+) -> io::Result<Vec<CacheUsage>> {
+    let mut caches = Vec::new();
+    if settings.isolated_user_home {
+        let user_home = isolated_user_home(data_dir);
+        caches.push((
+            "Wrapper distributions",
+            user_home.join("wrapper").join("dists"),
+        ));
+        caches.push(("Gradle caches", user_home.join("caches")));
+        caches.push(("Gradle daemons", user_home.join("daemon")));
+    }
+    caches.push(("Local build cache", local_build_cache_dir(data_dir)));
+    caches.push(("Managed Gradle installs", data_dir.join("gradle")));
+    caches.push(("Gradle metadata", http_cache_dir(data_dir)));
+
+    let mut usage = Vec::with_capacity(caches.len());
+    for (name, path) in caches {
+        let size = dir_size(&path).await?;
+        usage.push(CacheUsage { name, path, size });
+    }
+    Ok(usage)
+}
+
+/// Remove what the current projects no longer use, returning the number of bytes freed.
+///
+/// `projects` are the component repositories with their wrapper config. In an isolated
+/// user home this removes wrapper distributions and per-version caches of other Gradle
+/// versions; shared caches such as `modules-2` are cleaned up by Gradle itself.
+/// Build cache entries not used within `max_age` and stale managed installs are removed
+/// in either mode.
+pub async fn prune_caches(
+    data_dir: &Path,
+    settings: &GradleSettings,
+    projects: &[(&Path, WrapperProperties)],
+    max_age: Duration,
+) -> io::Result<u64> {
+    let mut freed = 0;
+    let managed_version = settings
+        .version
+        .as_deref()
+        .unwrap_or(DEFAULT_GRADLE_VERSION);
+
+    if settings.isolated_user_home {
+        let user_home = gradle_user_home(data_dir, settings);
+
+        let used_dists: HashSet<PathBuf> = projects
+            .iter()
+            .map(|(project_path, wrapper)| wrapper.distribution_dir(project_path, &user_home))
+            .collect();
+        freed += prune_wrapper_dists(&user_home.join("wrapper").join("dists"), &used_dists).await?;
+
+        let mut used_versions: HashSet<&str> = projects
+            .iter()
+            .filter_map(|(_, wrapper)| wrapper.gradle_version())
+            .collect();
+        used_versions.insert(managed_version);
+        for dir in [user_home.join("caches"), user_home.join("daemon")] {
+            freed += prune_version_dirs(&dir, &used_versions, max_age).await?;
+        }
+    } else {
+        info!("The Gradle user home is shared, leaving it untouched");
+    }
+
+    freed += remove_files_older_than(&local_build_cache_dir(data_dir), max_age).await?;
+
+    // managed installs of other versions, and leftovers of interrupted installs
+    let installs_dir = data_dir.join("gradle");
+    let current_install = format!("gradle-{managed_version}");
+    for (name, path) in list_dir(&installs_dir).await? {
+        if name != current_install {
+            freed += remove(&path).await?;
+        }
+    }
+
+    Ok(freed)
+}
+
+/// Remove `<dist name>/<url hash>` directories not in `used`
+async fn prune_wrapper_dists(dists_dir: &Path, used: &HashSet<PathBuf>) -> io::Result<u64> {
+    let mut freed = 0;
+    for (_, dist_dir) in list_dir(dists_dir).await? {
+        let mut remaining = 0;
+        for (_, hash_dir) in list_dir(&dist_dir).await? {
+            if used.contains(&hash_dir) {
+                remaining += 1;
+            } else {
+                freed += remove(&hash_dir).await?;
+            }
+        }
+        if remaining == 0 {
+            freed += remove(&dist_dir).await?;
+        }
+    }
+    Ok(freed)
+}
+
+/// Remove per-version directories (e.g. `caches/8.5`) of unused versions
+/// that were not touched within `max_age`
+async fn prune_version_dirs(
+    dir: &Path,
+    used_versions: &HashSet<&str>,
+    max_age: Duration,
+) -> io::Result<u64> {
+    let cutoff = SystemTime::now()
+        .checked_sub(max_age)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    let mut freed = 0;
+    for (name, path) in list_dir(dir).await? {
+        let is_version = name.starts_with(|c: char| c.is_ascii_digit());
+        if !is_version || used_versions.contains(name.as_str()) {
+            continue;
+        }
+        if fs::metadata(&path).await?.modified()? < cutoff {
+            freed += remove(&path).await?;
+        }
+    }
+    Ok(freed)
+}
+
+async fn remove(path: &Path) -> io::Result<u64> {
+    debug!("Remove {}", path.display());
+    remove_path(path).await
+}
+
+/// The entries of `dir` as `(file name, path)`, empty if it does not exist
+async fn list_dir(dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut result = Vec::new();
+    if !fs::try_exists(dir).await? {
+        return Ok(result);
+    }
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        result.push((
+            entry.file_name().to_string_lossy().into_owned(),
+            entry.path(),
+        ));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_prune_caches() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let data_dir = data_dir.path();
+        let settings = GradleSettings {
+            isolated_user_home: true,
+            ..Default::default()
+        };
+        let wrapper = WrapperProperties::parse(
+            "distributionUrl=https\\://services.gradle.org/distributions/gradle-8.5-bin.zip\n",
+        )
+        .unwrap();
+        let user_home = isolated_user_home(data_dir);
+        let used_dist = wrapper.distribution_dir(Path::new("/project"), &user_home);
+        let old_dist = user_home.join("wrapper/dists/gradle-7.6-bin/abc");
+        let used_caches = user_home.join("caches/8.5");
+        let old_caches = user_home.join("caches/7.6");
+        let shared_caches = user_home.join("caches/modules-2");
+        let old_install = data_dir.join("gradle/gradle-7.6");
+        for dir in [
+            &used_dist,
+            &old_dist,
+            &used_caches,
+            &old_caches,
+            &shared_caches,
+            &old_install,
+        ] {
+            std::fs::create_dir_all(dir).unwrap();
+            std::fs::write(dir.join("file"), b"data").unwrap();
+        }
+
+        let projects = [(Path::new("/project"), wrapper)];
+        let freed = prune_caches(data_dir, &settings, &projects, Duration::ZERO)
+            .await
+            .unwrap();
+
+        assert_eq!(freed, 12);
+        assert!(used_dist.exists());
+        assert!(!old_dist.parent().unwrap().exists());
+        assert!(used_caches.exists());
+        assert!(!old_caches.exists());
+        assert!(shared_caches.exists());
+        assert!(!old_install.exists());
+    }
+}
@@ -0,0 +1,147 @@
+use crate::building::gradle::cache::http_cache_dir;
+use crate::utils::archive::extract_zip;
+use crate::utils::download::conditional::ConditionalCache;
+use crate::utils::download::manager::{Artifact, DownloadManager};
+use crate::utils::hashing::Hash;
+use crate::utils::path_lock::lock_path;
+use anyhow::Context;
+use log::info;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Used for managed installs when no version is configured
+pub const DEFAULT_GRADLE_VERSION: &str = "8.14.3";
+const GRADLE_DISTRIBUTIONS_URL: &str = "https://services.gradle.org/distributions";
+
+/// A Gradle installation used for projects that do not ship a wrapper
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NativeGradle {
+    /// `gradle` found in `PATH`
+    System(PathBuf),
+    /// A distribution downloaded into the bootstrap directory
+    Managed {
+        version: String,
+        executable: PathBuf,
+    },
+}
+
+impl NativeGradle {
+    pub fn executable(&self) -> &Path {
+        match self {
+            NativeGradle::System(executable) => executable,
+            NativeGradle::Managed { executable, .. } => executable,
+        }
+    }
+}
+
+impl fmt::Display for NativeGradle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NativeGradle::System(executable) => {
+                write!(f, "system Gradle {}", executable.display())
+            }
+            NativeGradle::Managed {
+                version,
+                executable,
+            } => write!(f, "managed Gradle {version} {}", executable.display()),
+        }
+    }
+}
+
+/// Pick a Gradle installation for a project without wrapper.
+///
+/// A pinned `version` always uses a managed install, otherwise `gradle` from `PATH`
+/// is preferred and a managed install of [DEFAULT_GRADLE_VERSION] is the last resort.
+pub async fn resolve_native_gradle(
+    downloads: &DownloadManager,
+    data_dir: &Path,
+    version: Option<&str>,
+    mirror: Option<&str>,
+) -> anyhow::Result<NativeGradle> {
+    if version.is_none()
+        && let Ok(executable) = which::which("gradle")
+    {
+        return Ok(NativeGradle::System(executable));
+    }
+
+    let version = version.unwrap_or(DEFAULT_GRADLE_VERSION);
+    let executable = install_managed_gradle(downloads, data_dir, version, mirror).await?;
+    Ok(NativeGradle::Managed {
+        version: version.to_string(),
+        executable,
+    })
+}
+
+/// Download and unpack `gradle-<version>-bin.zip` into `<data_dir>/gradle/gradle-<version>`,
+/// returning the path of its launcher script.
+///
+/// The archive is fetched from `mirror` first, then from the configured download mirrors.
+pub async fn install_managed_gradle(
+    downloads: &DownloadManager,
+    data_dir: &Path,
+    version: &str,
+    mirror: Option<&str>,
+) -> anyhow::Result<PathBuf> {
+    let installs_dir = data_dir.join("gradle");
+    let gradle_home = installs_dir.join(format!("gradle-{version}"));
+    let executable = gradle_home.join("bin").join(if cfg!(windows) {
+        "gradle.bat"
+    } else {
+        "gradle"
+    });
+    // another component may be installing the same version right now
+    let _guard = lock_path(&gradle_home).await;
+    if fs::try_exists(&executable).await? {
+        return Ok(executable);
+    }
+
+    let file_name = format!("gradle-{version}-bin.zip");
+    let url = format!("{GRADLE_DISTRIBUTIONS_URL}/{file_name}");
+    // checksums always come from the official server, mirrors are not trusted
+    let checksum_url = format!("{url}.sha256");
+    let checksum = ConditionalCache::new(http_cache_dir(data_dir))
+        .fetch(downloads.client(), &checksum_url)
+        .await?
+        .text();
+    let expected_hash = Hash::from_hex("sha256", &checksum)
+        .with_context(|| format!("Bad checksum from {checksum_url}"))?;
+
+    let mut mirrors: Vec<String> = mirror
+        .map(|mirror| format!("{}/{file_name}", mirror.trim_end_matches('/')))
+        .into_iter()
+        .collect();
+    mirrors.extend(downloads.mirror_urls(&url));
+    let artifact = Artifact {
+        name: file_name.clone(),
+        mirrors,
+        hash: Some(expected_hash),
+        size: None,
+    };
+
+    info!("Downloading Gradle {version}");
+    let zip_path = installs_dir.join(&file_name);
+    downloads
+        .download(&artifact, &zip_path)
+        .await
+        .with_context(|| format!("Failed to download {url}"))?;
+
+    // unpack next to the final location, then move it in place at once
+    let unpack_dir = installs_dir.join(format!(".gradle-{version}.tmp"));
+    if fs::try_exists(&unpack_dir).await? {
+        fs::remove_dir_all(&unpack_dir).await?;
+    }
+    let result = extract_zip(&zip_path, &unpack_dir).await;
+    fs::remove_file(&zip_path).await?;
+    result.context("Failed to unpack Gradle distribution")?;
+
+    let unpacked_home = unpack_dir.join(format!("gradle-{version}"));
+    if fs::try_exists(&gradle_home).await? {
+        fs::remove_dir_all(&gradle_home).await?;
+    }
+    fs::rename(&unpacked_home, &gradle_home).await?;
+    fs::remove_dir_all(&unpack_dir).await?;
+
+    info!("Installed Gradle {version} to {}", gradle_home.display());
+    Ok(executable)
+}
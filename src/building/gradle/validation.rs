@@ -0,0 +1,276 @@
+use crate::building::gradle::wrapper::WrapperProperties;
+use crate::utils::download::conditional::ConditionalCache;
+use crate::utils::hashing::{Hash, calculate_file_hash, compare_file_hash};
+use crate::utils::path_lock::lock_path;
+use anyhow::Context;
+use futures_util::{StreamExt, stream};
+use log::{info, warn};
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Hosts the version list and the wrapper checksums
+pub const GRADLE_SERVICES_URL: &str = "https://services.gradle.org";
+/// Lists every released Gradle version together with its wrapper checksum URL
+const GRADLE_VERSIONS_PATH: &str = "/versions/all";
+/// Per-version wrapper checksum, `{version}` is replaced with the Gradle version
+const WRAPPER_CHECKSUM_PATH: &str = "/distributions/gradle-{version}-wrapper.jar.sha256";
+
+/// The outcome of checking a `gradle-wrapper.jar`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WrapperValidation {
+    /// The jar matches an official (or explicitly trusted) checksum
+    Trusted,
+    /// The jar does not match any known checksum, contains its SHA-256
+    Unknown(String),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GradleVersion {
+    wrapper_checksum_url: Option<String>,
+}
+
+/// Verifies wrapper jars against the checksums published by Gradle.
+///
+/// Official checksums that were seen once are remembered in `cache_path`,
+/// so the full version list is only fetched for jars we have never seen. The list
+/// and the checksum files are kept in `http_cache` and only transferred again once
+/// they change, a stale copy is used while services.gradle.org is unreachable.
+pub struct WrapperValidator<'a> {
+    pub client: &'a Client,
+    /// Usually [GRADLE_SERVICES_URL]
+    pub services_url: &'a str,
+    pub cache_path: PathBuf,
+    pub http_cache: ConditionalCache,
+    /// Extra checksums trusted by the user
+    pub trusted_checksums: &'a [Hash],
+    /// Unknown jars will run anyway, so skip the full version list and never fail
+    /// because services.gradle.org is unreachable
+    pub allow_unknown: bool,
+}
+
+impl WrapperValidator<'_> {
+    pub async fn validate(
+        &self,
+        jar_path: &Path,
+        wrapper: Option<&WrapperProperties>,
+    ) -> anyhow::Result<WrapperValidation> {
+        // concurrent builds share the checksum cache file
+        let _guard = lock_path(&self.cache_path).await;
+        for checksum in self.trusted_checksums {
+            if compare_file_hash(jar_path, checksum).await.is_ok() {
+                return Ok(WrapperValidation::Trusted);
+            }
+        }
+        let actual = calculate_file_hash(jar_path, "SHA256").await?;
+        let actual = actual.value().to_lowercase();
+
+        let mut known = self.load_cache().await?;
+        if known.contains(&actual) {
+            return Ok(WrapperValidation::Trusted);
+        }
+
+        // most projects ship the wrapper jar of the version they use, try that one first
+        if let Some(version) = wrapper.and_then(WrapperProperties::gradle_version) {
+            let url = format!(
+                "{}{}",
+                self.services_url,
+                WRAPPER_CHECKSUM_PATH.replace("{version}", version)
+            );
+            match self.fetch_checksum(&url).await {
+                Ok(checksum) if checksum == actual => {
+                    known.insert(checksum);
+                    self.save_cache(&known).await?;
+                    return Ok(WrapperValidation::Trusted);
+                }
+                Ok(_) => (),
+                Err(err) => warn!("Failed to fetch wrapper checksum for Gradle {version}: {err}"),
+            }
+        }
+
+        if self.allow_unknown {
+            return Ok(WrapperValidation::Unknown(actual));
+        }
+
+        info!("Fetching the official Gradle wrapper checksums");
+        known.extend(self.fetch_all_checksums().await?);
+        self.save_cache(&known).await?;
+
+        if known.contains(&actual) {
+            Ok(WrapperValidation::Trusted)
+        } else {
+            Ok(WrapperValidation::Unknown(actual))
+        }
+    }
+
+    async fn fetch_checksum(&self, url: &str) -> anyhow::Result<String> {
+        let body = self.http_cache.fetch(self.client, url).await?.text();
+        Ok(body.trim().to_lowercase())
+    }
+
+    async fn fetch_all_checksums(&self) -> anyhow::Result<Vec<String>> {
+        let url = format!("{}{GRADLE_VERSIONS_PATH}", self.services_url);
+        let body = self.http_cache.fetch(self.client, &url).await?.text();
+        let versions: Vec<GradleVersion> =
+            serde_json::from_str(&body).context("Bad Gradle version list")?;
+
+        let urls: BTreeSet<String> = versions
+            .into_iter()
+            .filter_map(|version| version.wrapper_checksum_url)
+            .collect();
+
+        // many versions share one wrapper jar, but every version has its own checksum file
+        let checksums: Vec<String> = stream::iter(urls)
+            .map(|url| async move { self.fetch_checksum(&url).await })
+            .buffer_unordered(16)
+            .filter_map(|result| async move { result.ok() })
+            .collect()
+            .await;
+        Ok(checksums)
+    }
+
+    async fn load_cache(&self) -> anyhow::Result<BTreeSet<String>> {
+        if !fs::try_exists(&self.cache_path).await? {
+            return Ok(BTreeSet::new());
+        }
+        let content = fs::read_to_string(&self.cache_path).await?;
+        Ok(content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_lowercase)
+            .collect())
+    }
+
+    async fn save_cache(&self, checksums: &BTreeSet<String>) -> anyhow::Result<()> {
+        if let Some(parent) = self.cache_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let mut content = String::new();
+        for checksum in checksums {
+            content.push_str(checksum);
+            content.push('\n');
+        }
+        fs::write(&self.cache_path, content).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::download::test_server::{TestServer, test_client};
+    use sha2::{Digest, Sha256};
+
+    fn sha256(data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
+    }
+
+    #[tokio::test]
+    async fn test_validate_wrapper_jar() {
+        let server = TestServer::start(Vec::new()).await;
+        let services_url = format!("http://{}", server.addr);
+        server.route(
+            "/versions/all",
+            format!(
+                r#"[{{"wrapperChecksumUrl": "{services_url}/old.sha256"}}, {{"wrapperChecksumUrl": null}}]"#
+            ),
+        );
+        server.route("/old.sha256", sha256(b"old wrapper"));
+        server.route(
+            "/distributions/gradle-8.5-wrapper.jar.sha256",
+            sha256(b"8.5 wrapper"),
+        );
+        let wrapper = WrapperProperties::parse(
+            "distributionUrl=https\\://services.gradle.org/distributions/gradle-8.5-bin.zip",
+        )
+        .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let client = test_client();
+        let trusted: Vec<Hash> = vec![
+            sha256(b"trusted wrapper").to_uppercase().parse().unwrap(),
+            format!("sha1:{}", hex::encode(sha1::Sha1::digest(b"sha1 wrapper")))
+                .parse()
+                .unwrap(),
+        ];
+        let validator = WrapperValidator {
+            client: &client,
+            services_url: &services_url,
+            cache_path: dir.path().join("checksums.txt"),
+            http_cache: ConditionalCache::new(dir.path().join("http")),
+            trusted_checksums: &trusted,
+            allow_unknown: false,
+        };
+        let validate = async |content: &[u8], wrapper: Option<&WrapperProperties>| {
+            let jar = dir.path().join("gradle-wrapper.jar");
+            fs::write(&jar, content).await.unwrap();
+            validator.validate(&jar, wrapper).await.unwrap()
+        };
+
+        // trusted by the user, no request needed
+        assert_eq!(
+            validate(b"trusted wrapper", None).await,
+            WrapperValidation::Trusted
+        );
+        assert_eq!(
+            validate(b"sha1 wrapper", None).await,
+            WrapperValidation::Trusted
+        );
+        assert_eq!(server.get_requests(), 0);
+
+        // the checksum of the wrapper's own Gradle version is tried first
+        assert_eq!(
+            validate(b"8.5 wrapper", Some(&wrapper)).await,
+            WrapperValidation::Trusted
+        );
+        assert_eq!(server.get_requests(), 1);
+
+        // known from the full version list
+        assert_eq!(
+            validate(b"old wrapper", Some(&wrapper)).await,
+            WrapperValidation::Trusted
+        );
+        assert_eq!(
+            validate(b"evil wrapper", None).await,
+            WrapperValidation::Unknown(sha256(b"evil wrapper"))
+        );
+
+        // remembered, no request needed
+        let requests = server.get_requests();
+        assert_eq!(
+            validate(b"old wrapper", None).await,
+            WrapperValidation::Trusted
+        );
+        assert_eq!(server.get_requests(), requests);
+    }
+
+    #[tokio::test]
+    async fn test_allow_unknown_wrapper_without_network() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = test_client();
+        let validator = WrapperValidator {
+            client: &client,
+            // nothing listens on port 1
+            services_url: "http://127.0.0.1:1",
+            cache_path: dir.path().join("checksums.txt"),
+            http_cache: ConditionalCache::new(dir.path().join("http")),
+            trusted_checksums: &[],
+            allow_unknown: true,
+        };
+        let wrapper = WrapperProperties::parse(
+            "distributionUrl=https\\://services.gradle.org/distributions/gradle-8.5-bin.zip",
+        )
+        .unwrap();
+        let jar = dir.path().join("gradle-wrapper.jar");
+        fs::write(&jar, b"unknown wrapper").await.unwrap();
+
+        assert_eq!(
+            validator.validate(&jar, Some(&wrapper)).await.unwrap(),
+            WrapperValidation::Unknown(sha256(b"unknown wrapper"))
+        );
+    }
+}
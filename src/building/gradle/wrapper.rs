@@ -0,0 +1,297 @@
+use crate::utils::disk::with_suffix;
+use crate::utils::download::manager::{Artifact, DownloadManager};
+use crate::utils::hashing::{Hash, compare_file_hash};
+use crate::utils::path_lock::lock_path;
+use crate::utils::properties::parse_properties;
+use anyhow::Context;
+use log::{info, warn};
+use md5::{Digest, Md5};
+use reqwest::Url;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// The default distribution and zip store path, relative to the store base
+const DEFAULT_STORE_PATH: &str = "wrapper/dists";
+
+/// The parsed content of `gradle/wrapper/gradle-wrapper.properties`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WrapperProperties {
+    pub distribution_url: String,
+    pub distribution_sha256_sum: Option<Hash>,
+    pub distribution_base: StoreBase,
+    pub distribution_path: String,
+    pub zip_store_base: StoreBase,
+    pub zip_store_path: String,
+}
+
+/// The directory a wrapper store path is relative to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreBase {
+    GradleUserHome,
+    Project,
+}
+
+impl StoreBase {
+    fn parse(value: Option<&String>) -> Self {
+        match value.map(String::as_str) {
+            Some("PROJECT") => StoreBase::Project,
+            _ => StoreBase::GradleUserHome,
+        }
+    }
+}
+
+/// Location of `gradle-wrapper.jar` inside a project
+pub fn wrapper_jar_path(project_path: &Path) -> PathBuf {
+    project_path
+        .join("gradle")
+        .join("wrapper")
+        .join("gradle-wrapper.jar")
+}
+
+impl WrapperProperties {
+    /// Location of the properties file inside a project
+    pub fn path_in(project_path: &Path) -> PathBuf {
+        project_path
+            .join("gradle")
+            .join("wrapper")
+            .join("gradle-wrapper.properties")
+    }
+
+    /// Load the wrapper properties of a project, `None` if the project has no wrapper config
+    pub async fn load(project_path: &Path) -> anyhow::Result<Option<Self>> {
+        let path = Self::path_in(project_path);
+        if !fs::try_exists(&path).await? {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path).await?;
+        Self::parse(&content)
+            .map(Some)
+            .with_context(|| format!("Bad wrapper properties {}", path.display()))
+    }
+
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let properties = parse_properties(content);
+        let distribution_url = properties
+            .get("distributionUrl")
+            .context("distributionUrl is missing")?
+            .to_owned();
+
+        Ok(Self {
+            distribution_url,
+            distribution_sha256_sum: properties
+                .get("distributionSha256Sum")
+                .map(|sum| Hash::from_hex("sha256", sum))
+                .transpose()
+                .context("Bad distributionSha256Sum")?,
+            distribution_base: StoreBase::parse(properties.get("distributionBase")),
+            distribution_path: properties
+                .get("distributionPath")
+                .cloned()
+                .unwrap_or_else(|| DEFAULT_STORE_PATH.to_string()),
+            zip_store_base: StoreBase::parse(properties.get("zipStoreBase")),
+            zip_store_path: properties
+                .get("zipStorePath")
+                .cloned()
+                .unwrap_or_else(|| DEFAULT_STORE_PATH.to_string()),
+        })
+    }
+
+    /// The file name of the distribution archive, e.g. `gradle-8.5-bin.zip`
+    pub fn distribution_file_name(&self) -> &str {
+        self.distribution_url
+            .rsplit('/')
+            .next()
+            .unwrap_or(&self.distribution_url)
+    }
+
+    /// The distribution name, the file name without `.zip`, e.g. `gradle-8.5-bin`
+    pub fn distribution_name(&self) -> &str {
+        let file_name = self.distribution_file_name();
+        file_name.strip_suffix(".zip").unwrap_or(file_name)
+    }
+
+    /// The Gradle version of the distribution, e.g. `8.5` for `gradle-8.5-bin.zip`
+    pub fn gradle_version(&self) -> Option<&str> {
+        let name = self.distribution_name().strip_prefix("gradle-")?;
+        Some(
+            name.strip_suffix("-bin")
+                .or_else(|| name.strip_suffix("-all"))
+                .unwrap_or(name),
+        )
+    }
+
+    /// The path the wrapper expects the downloaded distribution archive at.
+    ///
+    /// This mirrors `PathAssembler` of the Gradle wrapper:
+    /// `<zipStoreBase>/<zipStorePath>/<dist name>/<url hash>/<dist name>.zip`
+    pub fn distribution_zip_path(&self, project_path: &Path, gradle_user_home: &Path) -> PathBuf {
+        let base = match self.zip_store_base {
+            StoreBase::GradleUserHome => gradle_user_home,
+            StoreBase::Project => project_path,
+        };
+        base.join(&self.zip_store_path)
+            .join(self.distribution_name())
+            .join(wrapper_url_hash(&self.distribution_url))
+            .join(self.distribution_file_name())
+    }
+
+    /// The directory the wrapper unpacks the distribution into:
+    /// `<distributionBase>/<distributionPath>/<dist name>/<url hash>`
+    pub fn distribution_dir(&self, project_path: &Path, gradle_user_home: &Path) -> PathBuf {
+        let base = match self.distribution_base {
+            StoreBase::GradleUserHome => gradle_user_home,
+            StoreBase::Project => project_path,
+        };
+        base.join(&self.distribution_path)
+            .join(self.distribution_name())
+            .join(wrapper_url_hash(&self.distribution_url))
+    }
+
+    /// Download the distribution into the wrapper store, so the wrapper itself never
+    /// has to reach the network.
+    ///
+    /// If `mirror` is set, the archive is fetched from `<mirror>/<file name>` first,
+    /// then from the mirrors configured for `distributionUrl` and the URL itself. It is
+    /// always stored at the location the wrapper derives from the original URL.
+    pub async fn provision_distribution(
+        &self,
+        downloads: &DownloadManager,
+        project_path: &Path,
+        gradle_user_home: &Path,
+        mirror: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let zip_path = self.distribution_zip_path(project_path, gradle_user_home);
+        // another component may be provisioning the same distribution right now
+        let _guard = lock_path(&zip_path).await;
+        let expected_hash = self.distribution_sha256_sum.clone();
+
+        let marker_path = with_suffix(&zip_path, ".ok");
+        if fs::try_exists(&marker_path).await? {
+            // the wrapper has already unpacked this distribution
+            return Ok(());
+        }
+        if fs::try_exists(&zip_path).await? {
+            match &expected_hash {
+                None => return Ok(()),
+                Some(hash) => match compare_file_hash(&zip_path, hash).await {
+                    Ok(()) => return Ok(()),
+                    Err(err) => {
+                        warn!("Discard broken Gradle distribution: {err}");
+                        fs::remove_file(&zip_path).await?;
+                    }
+                },
+            }
+        }
+
+        let mut mirrors: Vec<String> = mirror
+            .map(|mirror| {
+                format!(
+                    "{}/{}",
+                    mirror.trim_end_matches('/'),
+                    self.distribution_file_name()
+                )
+            })
+            .into_iter()
+            .collect();
+        mirrors.extend(downloads.mirror_urls(&self.distribution_url));
+        let artifact = Artifact {
+            name: self.distribution_file_name().to_string(),
+            mirrors,
+            hash: expected_hash,
+            size: None,
+        };
+        info!("Downloading Gradle distribution {}", artifact.name);
+
+        // the archive only appears at its final path once complete,
+        // so the wrapper never sees a partial one
+        let served_by = downloads
+            .download(&artifact, &zip_path)
+            .await
+            .with_context(|| format!("Failed to download {}", self.distribution_url))?;
+        info!(
+            "Gradle distribution saved to {} (from {served_by})",
+            zip_path.display()
+        );
+        Ok(())
+    }
+}
+
+/// Hash a distribution URL like the Gradle wrapper does:
+/// the MD5 digest of the URL (without user info), printed in base 36.
+pub fn wrapper_url_hash(distribution_url: &str) -> String {
+    let safe_url = match Url::parse(distribution_url) {
+        Ok(mut url) if !url.username().is_empty() || url.password().is_some() => {
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+            url.to_string()
+        }
+        _ => distribution_url.to_string(),
+    };
+
+    let digest = Md5::digest(safe_url.as_bytes());
+    let mut value = u128::from_be_bytes(digest.into());
+    if value == 0 {
+        return "0".to_string();
+    }
+    let mut digits = Vec::new();
+    while value > 0 {
+        digits.push(std::char::from_digit((value % 36) as u32, 36).unwrap());
+        value /= 36;
+    }
+    digits.iter().rev().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrapper_url_hash() {
+        assert_eq!(
+            wrapper_url_hash("https://services.gradle.org/distributions/gradle-8.5-bin.zip"),
+            "5t9huq95ubn472n8rpzujfbqh"
+        );
+        assert_eq!(
+            wrapper_url_hash("https://services.gradle.org/distributions/gradle-8.14.3-bin.zip"),
+            "cv11ve7ro1n3o1j4so8xd9n66"
+        );
+    }
+
+    #[test]
+    fn test_distribution_zip_path() {
+        let properties = WrapperProperties::parse(
+            "distributionBase=GRADLE_USER_HOME\n\
+             distributionPath=wrapper/dists\n\
+             distributionUrl=https\\://services.gradle.org/distributions/gradle-8.5-bin.zip\n\
+             distributionSha256Sum=9d926787066a081739e8200858338b4a69e837c3a821a33aca9db09dd4a41026\n\
+             zipStoreBase=GRADLE_USER_HOME\n\
+             zipStorePath=wrapper/dists\n",
+        )
+        .unwrap();
+
+        assert!(properties.distribution_sha256_sum.is_some());
+        assert!(
+            WrapperProperties::parse("distributionUrl=x\ndistributionSha256Sum=d41d8cd9").is_err()
+        );
+        assert_eq!(properties.gradle_version(), Some("8.5"));
+        for (url, version) in [
+            ("https://example.com/gradle-8.14.3-all.zip", Some("8.14.3")),
+            (
+                "https://example.com/gradle-9.0-rc-1-bin.zip",
+                Some("9.0-rc-1"),
+            ),
+            ("https://example.com/custom-dist.zip", None),
+        ] {
+            let properties = WrapperProperties {
+                distribution_url: url.to_string(),
+                ..properties.clone()
+            };
+            assert_eq!(properties.gradle_version(), version);
+        }
+        assert_eq!(
+            properties.distribution_zip_path(Path::new("/project"), Path::new("/home/.gradle")),
+            Path::new("/home/.gradle/wrapper/dists/gradle-8.5-bin/5t9huq95ubn472n8rpzujfbqh")
+                .join("gradle-8.5-bin.zip")
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use async_zip::error::ZipError;
+use async_zip::tokio::read::fs::ZipFileReader;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+const MANIFEST_PATH: &str = "META-INF/MANIFEST.MF";
+
+#[derive(Error, Debug)]
+pub enum ManifestError {
+    #[error("Failed to read jar {jar}")]
+    Unreadable {
+        jar: Box<PathBuf>,
+        #[source]
+        source: ZipError,
+    },
+
+    #[error("Jar {0} has no {MANIFEST_PATH}")]
+    MissingManifest(Box<PathBuf>),
+
+    #[error("Jar {jar} is missing the manifest attribute {}", attributes.join(" or "))]
+    MissingAttribute {
+        jar: Box<PathBuf>,
+        attributes: &'static [&'static str],
+    },
+}
+
+/// The main section of a jar's `META-INF/MANIFEST.MF`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JarManifest {
+    attributes: Vec<(String, String)>,
+}
+
+impl JarManifest {
+    /// Parse the main section of a manifest, individual sections are ignored
+    pub fn parse(content: &str) -> Self {
+        let mut attributes: Vec<(String, String)> = Vec::new();
+        for line in content.lines() {
+            if line.is_empty() {
+                // the main section ends at the first blank line
+                break;
+            }
+            if let Some(continuation) = line.strip_prefix(' ') {
+                // long values are wrapped, continuation lines start with a single space
+                if let Some((_, value)) = attributes.last_mut() {
+                    value.push_str(continuation);
+                }
+                continue;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                attributes.push((name.trim().to_string(), value.trim_start().to_string()));
+            }
+        }
+        Self { attributes }
+    }
+
+    /// Look up an attribute, names are case-insensitive
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Open a jar and read its manifest
+pub async fn read_jar_manifest(jar: &Path) -> Result<JarManifest, ManifestError> {
+    let unreadable = |source| ManifestError::Unreadable {
+        jar: Box::new(jar.to_owned()),
+        source,
+    };
+    let reader = ZipFileReader::new(jar).await.map_err(unreadable)?;
+
+    let index = reader
+        .file()
+        .entries()
+        .iter()
+        .position(|entry| {
+            entry
+                .filename()
+                .as_str()
+                .is_ok_and(|name| name.eq_ignore_ascii_case(MANIFEST_PATH))
+        })
+        .ok_or_else(|| ManifestError::MissingManifest(Box::new(jar.to_owned())))?;
+
+    let mut content = String::new();
+    reader
+        .reader_with_entry(index)
+        .await
+        .map_err(unreadable)?
+        .read_to_string_checked(&mut content)
+        .await
+        .map_err(unreadable)?;
+    Ok(JarManifest::parse(&content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = JarManifest::parse(
+            "Manifest-Version: 1.0\r\n\
+             Main-Class: org.cubewhy.celestial.\r\n LauncherMainKt\r\n\
+             Implementation-Version: 1.2.3\r\n\
+             \r\n\
+             Name: org/cubewhy/\r\n\
+             Sealed: true\r\n",
+        );
+
+        assert_eq!(
+            manifest.get("main-class"),
+            Some("org.cubewhy.celestial.LauncherMainKt")
+        );
+        assert_eq!(manifest.get("Implementation-Version"), Some("1.2.3"));
+        assert_eq!(manifest.get("Sealed"), None);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::building::{BuildContext, Builder, find_artifact, run_build_command};
+use crate::config::MavenConfig;
+use crate::java::JdkTrait;
+use log::info;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::ReadDirStream;
+
+/// Builds projects with the Maven wrapper (`mvnw`), or `mvn` from `PATH`
+pub struct MavenBuilder<'a> {
+    pub config: &'a MavenConfig,
+}
+
+/// Locate the Maven launcher for a project, preferring the project's wrapper script
+pub fn resolve_maven(project_path: &Path) -> anyhow::Result<PathBuf> {
+    let wrapper = project_path.join(if cfg!(windows) { "mvnw.cmd" } else { "mvnw" });
+    if wrapper.is_file() {
+        return Ok(wrapper);
+    }
+    which::which("mvn").map_err(|_| {
+        anyhow::anyhow!(
+            "{} has no Maven wrapper and no 'mvn' command could be found in your PATH",
+            project_path.display()
+        )
+    })
+}
+
+/// Generate the Maven arguments from the config
+pub fn generate_maven_args(config: &MavenConfig) -> Vec<String> {
+    // never wait for input, there is nobody to answer
+    let mut args = vec!["--batch-mode".to_string()];
+    if !config.profiles.is_empty() {
+        args.push("-P".to_string());
+        args.push(config.profiles.join(","));
+    }
+    args.extend(
+        config
+            .system_properties
+            .iter()
+            .map(|(key, value)| format!("-D{key}={value}")),
+    );
+    args.extend_from_slice(&config.args);
+    args.extend_from_slice(&config.goals);
+    args
+}
+
+/// Attached artifacts which are never the runnable jar
+fn is_secondary_artifact(file_name: &str) -> bool {
+    file_name.starts_with("original-")
+        || file_name.ends_with("-sources.jar")
+        || file_name.ends_with("-javadoc.jar")
+        || file_name.ends_with("-tests.jar")
+}
+
+impl Builder for MavenBuilder<'_> {
+    fn name(&self) -> &'static str {
+        "Maven"
+    }
+
+    async fn build(
+        &self,
+        context: &BuildContext<'_, impl JdkTrait>,
+        project_path: &Path,
+    ) -> anyhow::Result<PathBuf> {
+        let maven = resolve_maven(project_path)?;
+        let args = generate_maven_args(self.config);
+
+        // do cleanup first, jars of previous builds must not be picked up
+        let target_dir = project_path.join("target");
+        if fs::try_exists(&target_dir).await? {
+            let mut stream = ReadDirStream::new(fs::read_dir(&target_dir).await?);
+            while let Some(file) = stream.next().await {
+                let file = file?;
+                if file.path().extension().is_some_and(|ext| ext == "jar") {
+                    info!("Clean build file: {}", file.path().display());
+                    fs::remove_file(file.path()).await?;
+                }
+            }
+        }
+
+        info!("Spawning maven: {} {}", maven.display(), args.join(" "));
+
+        let mut command = tokio::process::Command::new(&maven);
+        command.args(&args);
+        command.current_dir(project_path);
+        command.env("JAVA_HOME", context.jdk.java_home());
+        if let Some(maven_opts) = &self.config.maven_opts {
+            command.env("MAVEN_OPTS", maven_opts);
+        }
+        run_build_command("Maven", &mut command, context.timeout, context.cancel).await?;
+
+        find_artifact(&target_dir, &self.config.artifact, is_secondary_artifact).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_generate_maven_args() {
+        let config = MavenConfig {
+            goals: vec!["package".to_string()],
+            profiles: vec!["release".to_string(), "shade".to_string()],
+            args: vec!["-DskipTests".to_string()],
+            system_properties: BTreeMap::from([("revision".to_string(), "1.0".to_string())]),
+            ..MavenConfig::default()
+        };
+        assert_eq!(
+            generate_maven_args(&config),
+            [
+                "--batch-mode",
+                "-P",
+                "release,shade",
+                "-Drevision=1.0",
+                "-DskipTests",
+                "package"
+            ]
+        );
+    }
+}
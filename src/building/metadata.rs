@@ -0,0 +1,52 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::fs;
+use tokio::sync::Mutex;
+
+/// Components may finish building at the same time, serialize updates of the store
+static STORE_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// What we know about an installed jar
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildMetadata {
+    /// The commit the jar was built from
+    pub commit: String,
+    /// `Implementation-Version` from the jar's manifest
+    pub implementation_version: Option<String>,
+    /// Unix timestamp of the build, in milliseconds
+    pub built_at: u64,
+}
+
+/// Build metadata of every component, stored as `build-metadata.json` in the bootstrap directory
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BuildMetadataStore {
+    pub components: BTreeMap<String, BuildMetadata>,
+}
+
+impl BuildMetadataStore {
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        if !fs::try_exists(path).await? {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path).await?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?).await?;
+        Ok(())
+    }
+
+    /// Record a fresh build of `component`
+    pub async fn record(
+        path: &Path,
+        component: &str,
+        metadata: BuildMetadata,
+    ) -> anyhow::Result<()> {
+        let _guard = STORE_LOCK.lock().await;
+        let mut store = Self::load(path).await?;
+        store.components.insert(component.to_string(), metadata);
+        store.save(path).await
+    }
+}
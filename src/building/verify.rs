@@ -0,0 +1,210 @@
+use crate::building::{BuildContext, Component, build_artifact};
+use crate::java::JdkTrait;
+use crate::utils::git::{clone_at_revision, head_commit};
+use anyhow::Context;
+use async_zip::error::ZipError;
+use async_zip::tokio::read::fs::ZipFileReader;
+use git2::Repository;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Entries that differ between two jars
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct JarDiff {
+    pub only_in_first: Vec<String>,
+    pub only_in_second: Vec<String>,
+    /// Entries present in both jars with different content
+    pub differing: Vec<String>,
+}
+
+impl JarDiff {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_first.is_empty() && self.only_in_second.is_empty() && self.differing.is_empty()
+    }
+}
+
+/// Compare two jars entry by entry.
+///
+/// Only names and uncompressed content are compared, timestamps,
+/// compression and entry order do not matter.
+pub async fn compare_jars(first: &Path, second: &Path) -> Result<JarDiff, ZipError> {
+    let first = jar_entry_digests(first).await?;
+    let mut second = jar_entry_digests(second).await?;
+
+    let mut diff = JarDiff::default();
+    for (name, digest) in first {
+        match second.remove(&name) {
+            None => diff.only_in_first.push(name),
+            Some(other) if other != digest => diff.differing.push(name),
+            Some(_) => {}
+        }
+    }
+    diff.only_in_second = second.into_keys().collect();
+    Ok(diff)
+}
+
+/// SHA-256 of every file entry of a jar, by name
+async fn jar_entry_digests(jar: &Path) -> Result<BTreeMap<String, Vec<u8>>, ZipError> {
+    let reader = ZipFileReader::new(jar).await?;
+    let mut digests = BTreeMap::new();
+    for (index, entry) in reader.file().entries().iter().enumerate() {
+        if entry.dir()? {
+            continue;
+        }
+        let name = entry.filename().as_str()?.to_string();
+        let mut content = Vec::new();
+        reader
+            .reader_with_entry(index)
+            .await?
+            .read_to_end_checked(&mut content)
+            .await?;
+        digests.insert(name, Sha256::digest(&content).to_vec());
+    }
+    Ok(digests)
+}
+
+/// Build `commit` of a component in a fresh working copy, and compare the jar with
+/// a second fresh build, or with `reference` if given.
+///
+/// Without `commit`, the commit checked out in the component's repository is used.
+/// The working copies live in `<data_dir>/verify` and are removed afterwards. The
+/// builds never use the build cache.
+pub async fn verify_build(
+    context: &BuildContext<'_, impl JdkTrait>,
+    component: &Component<'_>,
+    commit: Option<&str>,
+    reference: Option<&Path>,
+) -> anyhow::Result<JarDiff> {
+    let commit = match commit {
+        Some(commit) => commit.to_string(),
+        None => {
+            let repo_path = component.repo_path.clone();
+            tokio::task::spawn_blocking(move || head_commit(&Repository::open(repo_path)?))
+                .await?
+                .with_context(|| {
+                    format!(
+                        "No repository of {} found, run the bootstrap once first",
+                        component.name
+                    )
+                })?
+        }
+    };
+
+    let work_dir = context.data_dir.join("verify");
+    if fs::try_exists(&work_dir).await? {
+        fs::remove_dir_all(&work_dir).await?;
+    }
+
+    // outputs cached by the first build would make the second one identical for free
+    let context = &BuildContext {
+        no_build_cache: true,
+        ..*context
+    };
+    let result = async {
+        let first = build_fresh_copy(context, component, &commit, &work_dir.join("first")).await?;
+        let second = match reference {
+            Some(reference) => reference.to_owned(),
+            None => build_fresh_copy(context, component, &commit, &work_dir.join("second")).await?,
+        };
+        info!("Comparing {} with {}", first.display(), second.display());
+        Ok(compare_jars(&first, &second).await?)
+    }
+    .await;
+
+    // a failed cleanup must not hide the outcome, e.g. a clone that never created the directory
+    if let Err(err) = fs::remove_dir_all(&work_dir).await
+        && err.kind() != io::ErrorKind::NotFound
+    {
+        warn!("Failed to remove {}: {err}", work_dir.display());
+    }
+    result
+}
+
+/// Clone the component's repository at `commit` into `dest` and build it
+async fn build_fresh_copy(
+    context: &BuildContext<'_, impl JdkTrait>,
+    component: &Component<'_>,
+    commit: &str,
+    dest: &Path,
+) -> anyhow::Result<PathBuf> {
+    info!(
+        "Checking out {} {commit} into {}",
+        component.name,
+        dest.display()
+    );
+    let source = component.repo_path.to_string_lossy().into_owned();
+    let (dest_path, revision) = (dest.to_owned(), commit.to_string());
+    tokio::task::spawn_blocking(move || clone_at_revision(&source, &dest_path, &revision))
+        .await?
+        .with_context(|| format!("Failed to check out {commit}"))?;
+
+    let (artifact, _) = build_artifact(context, component, dest).await?;
+    Ok(artifact)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_zip::tokio::write::ZipFileWriter;
+    use async_zip::{Compression, ZipEntryBuilder};
+
+    async fn write_zip(path: &Path, entries: &[(&str, &[u8], Compression)]) {
+        let file = fs::File::create(path).await.unwrap();
+        let mut writer = ZipFileWriter::with_tokio(file);
+        for (name, data, compression) in entries {
+            let entry = ZipEntryBuilder::new((*name).into(), *compression);
+            writer.write_entry_whole(entry, data).await.unwrap();
+        }
+        writer.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compare_jars() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("first.jar");
+        let second = dir.path().join("second.jar");
+        write_zip(
+            &first,
+            &[
+                (
+                    "META-INF/MANIFEST.MF",
+                    b"Manifest-Version: 1.0\r\n",
+                    Compression::Deflate,
+                ),
+                ("a/A.class", b"A", Compression::Deflate),
+                ("a/B.class", b"B", Compression::Deflate),
+                ("removed.txt", b"", Compression::Deflate),
+            ],
+        )
+        .await;
+        // different order and compression, which must not count as a difference
+        write_zip(
+            &second,
+            &[
+                ("a/B.class", b"B2", Compression::Stored),
+                ("a/A.class", b"A", Compression::Stored),
+                (
+                    "META-INF/MANIFEST.MF",
+                    b"Manifest-Version: 1.0\r\n",
+                    Compression::Stored,
+                ),
+                ("added.txt", b"", Compression::Stored),
+            ],
+        )
+        .await;
+
+        assert!(compare_jars(&first, &first).await.unwrap().is_empty());
+        assert_eq!(
+            compare_jars(&first, &second).await.unwrap(),
+            JarDiff {
+                only_in_first: vec!["removed.txt".to_string()],
+                only_in_second: vec!["added.txt".to_string()],
+                differing: vec!["a/B.class".to_string()],
+            }
+        );
+    }
+}
@@ -1,4 +1,13 @@
-use clap::Parser;
+use crate::utils::hashing::Hash;
+use anyhow::Context;
+use clap::{Parser, Subcommand, ValueEnum};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs;
 
 #[derive(Parser, Debug)]
 pub struct ProgramParameters {
@@ -6,4 +15,426 @@ pub struct ProgramParameters {
     pub celestial_branch: String,
     #[clap(long, default_value = "main")]
     pub debugger_branch: String,
-}
\ No newline at end of file
+    /// Path to the bootstrap config file, defaults to `config.toml` in the bootstrap directory
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+    /// Run Gradle wrapper jars that do not match any official checksum
+    #[clap(long)]
+    pub allow_unknown_wrapper: bool,
+    /// How download progress is shown
+    #[clap(long, value_enum, default_value_t = ProgressOutput::Bars)]
+    pub progress: ProgressOutput,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressOutput {
+    /// Progress bars on the terminal
+    Bars,
+    /// One JSON event per line on stdout, for frontends
+    Json,
+    None,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Inspect or clean up the build caches
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+    /// Download the artifacts listed in a manifest file
+    Download {
+        /// TOML file with one `[[artifact]]` table per file
+        manifest: PathBuf,
+        /// Resolve relative destinations against this directory instead of the bootstrap directory
+        #[clap(long)]
+        base_dir: Option<PathBuf>,
+    },
+    /// Build a component twice from the same commit and compare the jars
+    VerifyBuild {
+        #[clap(value_enum, default_value_t = ComponentName::Celestial)]
+        component: ComponentName,
+        /// The commit to build, defaults to the one checked out
+        #[clap(long)]
+        commit: Option<String>,
+        /// Compare against this jar (e.g. a published release) instead of a second build
+        #[clap(long)]
+        reference: Option<PathBuf>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComponentName {
+    Celestial,
+    BrowserDebugger,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheAction {
+    /// Report the disk usage of the caches
+    Info,
+    /// Remove Gradle distributions and caches no longer in use, and evict download cache entries
+    Prune {
+        /// Keep build cache entries and caches used within this many days [default: 30].
+        /// Download cache entries use `download.cache_max_age_days` unless this is given
+        #[clap(long)]
+        max_age_days: Option<u64>,
+    },
+}
+
+/// Persistent bootstrap configuration, loaded from `config.toml`.
+///
+/// Every field is optional, a missing file behaves like an empty one.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct BootstrapConfig {
+    pub build: BuildSettings,
+    pub gradle: GradleSettings,
+    pub download: DownloadSettings,
+    pub http: HttpSettings,
+    pub celestial: ComponentConfig,
+    pub browser_debugger: ComponentConfig,
+}
+
+impl BootstrapConfig {
+    /// Load the config file, falling back to the defaults if it does not exist
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        if !fs::try_exists(path).await? {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path).await?;
+        toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+}
+
+/// Settings shared by every build
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct BuildSettings {
+    /// Kill a build (and every process it started) after this many seconds, `0` disables the timeout
+    pub timeout_secs: u64,
+    /// How many components may be built at the same time
+    pub max_parallel_builds: usize,
+}
+
+impl Default for BuildSettings {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 30 * 60,
+            max_parallel_builds: 1,
+        }
+    }
+}
+
+impl BuildSettings {
+    pub fn timeout(&self) -> Option<Duration> {
+        (self.timeout_secs > 0).then(|| Duration::from_secs(self.timeout_secs))
+    }
+}
+
+/// Settings shared by every Gradle build
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct GradleSettings {
+    /// Download Gradle distributions from `<mirror>/<file name>` instead of `distributionUrl`
+    pub distribution_mirror: Option<String>,
+    /// Gradle version installed for projects without a wrapper, `gradle` from `PATH` is used if unset
+    pub version: Option<String>,
+    /// Run Gradle wrapper jars that do not match any official checksum
+    pub allow_unknown_wrapper: bool,
+    /// Extra checksums of wrapper jars to trust, in any format [Hash] parses
+    pub trusted_wrapper_checksums: Vec<Hash>,
+    /// Use a Gradle user home inside the bootstrap directory instead of `~/.gradle`
+    pub isolated_user_home: bool,
+    /// Enable the Gradle build cache, stored inside the bootstrap directory
+    pub local_build_cache: bool,
+}
+
+/// Settings shared by every download
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct DownloadSettings {
+    /// Mirrors per URL prefix, tried in order before the original URL, e.g.
+    /// `"https://services.gradle.org/distributions" = ["https://mirror.example/gradle"]`
+    pub mirrors: BTreeMap<String, Vec<String>>,
+    /// Keep downloads with a known hash in a cache inside the bootstrap directory
+    pub cache: bool,
+    /// Evict the least recently used cache entries beyond this size, `0` disables the limit
+    pub cache_max_size_mb: u64,
+    /// Evict cache entries unused for this many days, `0` disables the limit
+    pub cache_max_age_days: u64,
+    /// Requests in flight at once across all downloads, `0` disables the limit
+    pub max_requests: usize,
+    /// Bandwidth shared by all downloads in KiB per second, `0` disables the limit
+    pub bandwidth_limit_kib: u64,
+}
+
+impl Default for DownloadSettings {
+    fn default() -> Self {
+        Self {
+            mirrors: BTreeMap::new(),
+            cache: true,
+            cache_max_size_mb: 4096,
+            cache_max_age_days: 90,
+            max_requests: 16,
+            bandwidth_limit_kib: 0,
+        }
+    }
+}
+
+impl DownloadSettings {
+    pub fn cache_max_size(&self) -> Option<u64> {
+        (self.cache_max_size_mb > 0).then(|| self.cache_max_size_mb * 1024 * 1024)
+    }
+
+    pub fn cache_max_age(&self) -> Option<Duration> {
+        (self.cache_max_age_days > 0)
+            .then(|| Duration::from_secs(self.cache_max_age_days * 24 * 60 * 60))
+    }
+
+    pub fn max_requests(&self) -> Option<usize> {
+        (self.max_requests > 0).then_some(self.max_requests)
+    }
+
+    /// The bandwidth limit in bytes per second
+    pub fn bandwidth_limit(&self) -> Option<u64> {
+        (self.bandwidth_limit_kib > 0).then(|| self.bandwidth_limit_kib * 1024)
+    }
+}
+
+/// Settings of the HTTP client used for every request
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HttpSettings {
+    /// Proxy for HTTP and HTTPS, e.g. `http://127.0.0.1:7890`.
+    /// `HTTP_PROXY`/`HTTPS_PROXY` from the environment are used if unset
+    pub proxy: Option<String>,
+    /// Hosts reached without the proxy, `NO_PROXY` from the environment is used if empty.
+    /// Only valid together with `proxy`
+    pub no_proxy: Vec<String>,
+    /// Extra trusted root certificates, PEM files with one or more certificates
+    pub ca_certificates: Vec<PathBuf>,
+    pub connect_timeout_secs: u64,
+    /// Give up on a connection that sends nothing for this long
+    pub read_timeout_secs: u64,
+    /// Use HTTP/2 where the server supports it, HTTP/1.1 otherwise
+    pub http2: bool,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            no_proxy: Vec::new(),
+            ca_certificates: Vec::new(),
+            connect_timeout_secs: 30,
+            read_timeout_secs: 60,
+            http2: true,
+        }
+    }
+}
+
+impl HttpSettings {
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout_secs)
+    }
+}
+
+/// Per-component build settings
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ComponentConfig {
+    /// The build tool, detected from the project files by default
+    pub builder: BuilderKind,
+    pub gradle: GradleConfig,
+    pub maven: MavenConfig,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuilderKind {
+    /// `build.gradle(.kts)` selects Gradle, `pom.xml` selects Maven
+    #[default]
+    Auto,
+    Gradle,
+    Maven,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct GradleConfig {
+    /// Gradle tasks to run, e.g. `shadowJar`
+    pub tasks: Vec<String>,
+    /// Extra command-line arguments, e.g. `-x test` or `--parallel`
+    pub args: Vec<String>,
+    /// Project properties, passed as `-Pkey=value`
+    pub project_properties: BTreeMap<String, String>,
+    /// System properties, passed as `-Dkey=value`
+    pub system_properties: BTreeMap<String, String>,
+    /// Overrides the `GRADLE_OPTS` environment variable
+    pub gradle_opts: Option<String>,
+    /// Overrides the `JAVA_OPTS` environment variable
+    pub java_opts: Option<String>,
+    /// Pattern used to locate the emitted jar inside `build/libs`
+    pub artifact: ArtifactPattern,
+}
+
+impl Default for GradleConfig {
+    fn default() -> Self {
+        Self {
+            tasks: vec!["build".to_string()],
+            args: Vec::new(),
+            project_properties: BTreeMap::new(),
+            system_properties: BTreeMap::new(),
+            gradle_opts: None,
+            java_opts: None,
+            artifact: ArtifactPattern::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct MavenConfig {
+    /// Maven goals to run, e.g. `package`
+    pub goals: Vec<String>,
+    /// Profiles to activate, passed as `-P a,b`
+    pub profiles: Vec<String>,
+    /// Extra command-line arguments, e.g. `-DskipTests`
+    pub args: Vec<String>,
+    /// System properties, passed as `-Dkey=value`
+    pub system_properties: BTreeMap<String, String>,
+    /// Overrides the `MAVEN_OPTS` environment variable
+    pub maven_opts: Option<String>,
+    /// Pattern used to locate the emitted jar inside `target`
+    pub artifact: ArtifactPattern,
+}
+
+impl Default for MavenConfig {
+    fn default() -> Self {
+        Self {
+            goals: vec!["package".to_string()],
+            profiles: Vec::new(),
+            args: Vec::new(),
+            system_properties: BTreeMap::new(),
+            maven_opts: None,
+            artifact: ArtifactPattern::glob("*.jar"),
+        }
+    }
+}
+
+/// A file name pattern, written as `{ glob = "..." }` or `{ regex = "..." }`.
+///
+/// Compiled when the config is loaded, so a bad pattern is reported before any build runs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "PatternSource")]
+pub enum ArtifactPattern {
+    Glob(glob::Pattern),
+    Regex(Regex),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PatternSource {
+    Glob(String),
+    Regex(String),
+}
+
+impl TryFrom<PatternSource> for ArtifactPattern {
+    type Error = String;
+
+    fn try_from(source: PatternSource) -> Result<Self, Self::Error> {
+        match source {
+            PatternSource::Glob(pattern) => glob::Pattern::new(&pattern)
+                .map(ArtifactPattern::Glob)
+                .map_err(|err| format!("Bad artifact glob {pattern}: {err}")),
+            PatternSource::Regex(pattern) => Regex::new(&pattern)
+                .map(ArtifactPattern::Regex)
+                .map_err(|err| format!("Bad artifact regex {pattern}: {err}")),
+        }
+    }
+}
+
+impl Default for ArtifactPattern {
+    fn default() -> Self {
+        ArtifactPattern::glob("*-fatjar*.jar")
+    }
+}
+
+impl ArtifactPattern {
+    /// A glob known to be valid
+    fn glob(pattern: &str) -> Self {
+        ArtifactPattern::Glob(glob::Pattern::new(pattern).unwrap())
+    }
+
+    /// Check whether the given file name matches this pattern
+    pub fn matches(&self, file_name: &str) -> bool {
+        match self {
+            ArtifactPattern::Glob(pattern) => pattern.matches(file_name),
+            ArtifactPattern::Regex(pattern) => pattern.is_match(file_name),
+        }
+    }
+}
+
+impl fmt::Display for ArtifactPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArtifactPattern::Glob(pattern) => write!(f, "glob {pattern}"),
+            ArtifactPattern::Regex(pattern) => write!(f, "regex {pattern}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_artifact_pattern_is_checked_on_load() {
+        let config: BootstrapConfig =
+            toml::from_str("[celestial.gradle]\nartifact = { regex = '^app-.*\\.jar$' }").unwrap();
+        assert!(config.celestial.gradle.artifact.matches("app-1.0.jar"));
+        assert!(!config.celestial.gradle.artifact.matches("app.zip"));
+
+        let result =
+            toml::from_str::<BootstrapConfig>("[celestial.gradle]\nartifact = { glob = '[' }");
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Bad artifact glob")
+        );
+    }
+
+    #[test]
+    fn test_trusted_wrapper_checksums_are_parsed() {
+        let config: BootstrapConfig = toml::from_str(
+            "[gradle]\ntrusted_wrapper_checksums = [\n\
+             'sha256-uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=',\n\
+             'SHA1:2AAE6C35C94FCFB415DBE95F408B9CE91EE846ED',\n\
+             ]",
+        )
+        .unwrap();
+        assert_eq!(
+            config.gradle.trusted_wrapper_checksums,
+            vec![
+                Hash::Sha256(
+                    "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9".to_string()
+                ),
+                Hash::Sha1("2aae6c35c94fcfb415dbe95f408b9ce91ee846ed".to_string()),
+            ]
+        );
+
+        let result = toml::from_str::<BootstrapConfig>(
+            "[gradle]\ntrusted_wrapper_checksums = ['not a checksum']",
+        );
+        assert!(result.is_err());
+    }
+}
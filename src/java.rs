@@ -1,20 +1,29 @@
-pub mod download;
 pub mod resolving;
 
-use crate::java::resolving::resolve_java_version;
+use crate::java::resolving::{resolve_java_home, resolve_java_version};
 use log::error;
 use std::path::{Path, PathBuf};
 
 pub trait JdkTrait {
+    /// The JDK installation directory, suitable for `JAVA_HOME`
+    fn java_home(&self) -> &Path;
+    /// The `java` launcher inside [JdkTrait::java_home]
     fn java_executable(&self) -> &Path;
     fn version(&self) -> i32;
 }
 
 pub struct Jdk {
+    java_home: PathBuf,
     java_executable: PathBuf,
     version: i32,
 }
 
+/// Locate the `java` launcher inside a JDK home
+pub fn java_executable_in(java_home: &Path) -> PathBuf {
+    let executable = if cfg!(windows) { "java.exe" } else { "java" };
+    java_home.join("bin").join(executable)
+}
+
 impl Jdk {
     /// Resolve Jdk
     pub async fn resolve_higher(minimalize_version: i32) -> Option<Self> {
@@ -36,8 +45,18 @@ impl Jdk {
                 continue;
             };
             if version >= minimalize_version {
+                let Ok(java_home) = resolve_java_home(&executable).await else {
+                    continue; // cannot locate JAVA_HOME
+                };
+                // prefer the launcher inside the home, `which` may return a symlink
+                let home_executable = java_executable_in(&java_home);
                 return Some(Self {
-                    java_executable: executable,
+                    java_executable: if home_executable.is_file() {
+                        home_executable
+                    } else {
+                        executable
+                    },
+                    java_home,
                     version,
                 });
             }
@@ -47,6 +66,10 @@ impl Jdk {
 }
 
 impl JdkTrait for Jdk {
+    fn java_home(&self) -> &Path {
+        self.java_home.as_ref()
+    }
+
     fn java_executable(&self) -> &Path {
         self.java_executable.as_ref()
     }
@@ -1,3 +0,0 @@
-pub fn download_jdk() {
-    todo!("not implemented yet");
-}
@@ -1,5 +1,5 @@
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use thiserror::Error;
@@ -12,6 +12,12 @@ static JAVA_VERSION_REGEX: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r#"(?:java|openjdk) version "([^"]+)""#).unwrap()
 });
 
+/// A regular expression to capture the `java.home` line from `java -XshowSettings:properties`.
+/// Example: `    java.home = /usr/lib/jvm/java-17-openjdk` -> captures `/usr/lib/jvm/java-17-openjdk`
+static JAVA_HOME_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?m)^\s*java\.home = (.+?)\s*$").unwrap()
+});
+
 /// Represents errors that can occur while resolving the Java version.
 #[derive(Debug, Error)]
 pub enum JavaVersionError {
@@ -31,6 +37,10 @@ pub enum JavaVersionError {
     /// The version string could not be found in the command's output.
     #[error("Could not find a version string in the output of 'java -version'.")]
     VersionNotFound,
+
+    /// The `java.home` property could not be found in the command's output.
+    #[error("Could not find java.home in the output of 'java -XshowSettings:properties'.")]
+    JavaHomeNotFound,
 }
 
 /// Asynchronously resolves the installed Java version by executing `java -version`.
@@ -91,4 +101,29 @@ pub async fn resolve_java_version(program_path: &Path) -> Result<String, JavaVer
             Err(JavaVersionError::VersionNotFound)
         }
     }
-}
\ No newline at end of file
+}
+
+/// Asynchronously resolves the installation directory (`JAVA_HOME`) of a `java` executable.
+///
+/// The executable found in `PATH` is often a symlink or a shim (e.g. `/usr/bin/java`),
+/// so the home is read from the `java.home` system property reported by the JVM itself
+/// instead of being derived from the executable path.
+pub async fn resolve_java_home(program_path: &Path) -> Result<PathBuf, JavaVersionError> {
+    let output = tokio::process::Command::new(program_path)
+        .arg("-XshowSettings:properties")
+        .arg("-version")
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(JavaVersionError::CommandFailed(stderr));
+    }
+
+    // The settings are printed to stderr, just like the version.
+    let stderr = String::from_utf8(output.stderr)?;
+    match JAVA_HOME_REGEX.captures(&stderr) {
+        Some(captures) => Ok(PathBuf::from(captures.get(1).unwrap().as_str())),
+        None => Err(JavaVersionError::JavaHomeNotFound),
+    }
+}
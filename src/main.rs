@@ -3,17 +3,40 @@ mod config;
 mod java;
 pub mod utils;
 
-use crate::building::gradle::build_with_gradle;
-use crate::config::ProgramParameters;
+use crate::building::gradle::cache::{CacheUsage, cache_usage, prune_caches};
+use crate::building::gradle::wrapper::WrapperProperties;
+use crate::building::verify::verify_build;
+use crate::building::{ArtifactKind, BuildContext, Component, build_component};
+use crate::config::{
+    BootstrapConfig, CacheAction, Command, ComponentName, GradleSettings, ProgramParameters,
+    ProgressOutput,
+};
 use crate::java::{Jdk, JdkTrait};
-use crate::utils::git::{FastForwardStatus, fast_forward};
+use crate::utils::disk::{dir_size, format_size};
+use crate::utils::download::batch::{BatchManifest, download_batch};
+use crate::utils::download::budget::{self, DownloadBudget};
+use crate::utils::download::cache::DownloadCache;
+use crate::utils::download::manager::DownloadManager;
+use crate::utils::download::progress::{self, JsonEventStream, ProgressBars};
+use crate::utils::git::{FastForwardStatus, fast_forward, head_commit};
+use crate::utils::http::build_client;
+use crate::utils::process::{protect_stdout, reserve_stdout};
+use anyhow::Context;
 use clap::Parser;
+use futures_util::future::join_all;
 use git2::Repository;
-use log::{error, info};
+use log::{error, info, warn};
 use std::path::{Path, PathBuf};
 use std::process::ExitStatus;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{env, io, process};
 use tokio::fs;
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+
+/// How long `cache prune` keeps unused Gradle caches by default
+const DEFAULT_PRUNE_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -41,11 +64,102 @@ async fn main() -> anyhow::Result<()> {
 
     // parse args
     let args = ProgramParameters::parse();
+    let config_path = args
+        .config
+        .clone()
+        .unwrap_or_else(|| base_dir.join("config.toml"));
+    let mut config = match BootstrapConfig::load(&config_path).await {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Failed to load config: {err:#}");
+            process::exit(1);
+        }
+    };
+    config.gradle.allow_unknown_wrapper |= args.allow_unknown_wrapper;
+
+    budget::configure(DownloadBudget::new(
+        config.download.max_requests(),
+        config.download.bandwidth_limit(),
+    ));
+
+    match args.progress {
+        ProgressOutput::Bars => progress::subscribe(Arc::new(ProgressBars::new())),
+        ProgressOutput::Json => {
+            // build tools and the launcher print to stderr instead
+            reserve_stdout();
+            progress::subscribe(Arc::new(JsonEventStream::new(io::stdout())));
+        }
+        ProgressOutput::None => {}
+    }
+
+    let celestial = Component {
+        name: "Celestial",
+        repository: "https://codeberg.org/earthsworth/celestial.git",
+        branch: &args.celestial_branch,
+        repo_path: base_dir.join("repositories").join("celestial"),
+        jar_path: base_dir.join("celestial.jar"),
+        kind: ArtifactKind::Application,
+        config: &config.celestial,
+    };
+    let debugger = Component {
+        name: "BrowserDebugger",
+        repository: "https://codeberg.org/earthsworth/BrowserDebugger.git",
+        branch: &args.debugger_branch,
+        repo_path: base_dir.join("repositories").join("browser-debugger"),
+        jar_path: javaagent_dir.join("browser-debugger.jar"),
+        kind: ArtifactKind::JavaAgent,
+        config: &config.browser_debugger,
+    };
+
+    let download_cache = DownloadCache::new(
+        base_dir.join("cache"),
+        config.download.cache_max_size(),
+        config.download.cache_max_age(),
+    );
+
+    if let Some(Command::Cache { action }) = &args.command {
+        return run_cache_command(
+            action,
+            &base_dir,
+            &config.gradle,
+            &download_cache,
+            &[&celestial, &debugger],
+        )
+        .await;
+    }
+
+    let client = match build_client(&config.http) {
+        Ok(client) => client,
+        Err(err) => {
+            error!("Failed to set up HTTP: {:#}", anyhow::Error::from(err));
+            process::exit(1);
+        }
+    };
+    let mut downloads = DownloadManager::new(client.clone(), &config.download.mirrors);
+    if config.download.cache {
+        downloads.cache = Some(download_cache);
+    }
+
+    if let Some(Command::Download {
+        manifest,
+        base_dir: batch_dir,
+    }) = &args.command
+    {
+        return run_download_command(
+            &downloads,
+            manifest,
+            batch_dir.as_deref().unwrap_or(&base_dir),
+        )
+        .await;
+    }
 
     info!("Welcome to Celestial Bootstrap Next!");
 
+    let cancel = CancellationToken::new();
+    cancel_on_ctrl_c(cancel.clone());
+
     let Some(jdk) = Jdk::resolve_higher(17).await else {
-        // TODO: download jdk
+        // TODO: download a JDK archive through `downloads`, so it gets mirrors and the cache
         error!("Celestial requires Jdk 17 or higher to run, please download one manually.");
         process::exit(1);
     };
@@ -56,53 +170,103 @@ async fn main() -> anyhow::Result<()> {
         jdk.java_executable().to_string_lossy()
     );
 
-    let celestial_jar_path = base_dir.join("celestial.jar");
-    let debugger_jar_path = javaagent_dir.join("browser-debugger.jar");
-
-    let is_first_run = !fs::try_exists(&celestial_jar_path).await?;
-
-    // update Celestial
-    info!("Check update for Celestial Launcher");
-    match check_update(
-        &base_dir.join("repositories").join("celestial"),
-        "https://codeberg.org/earthsworth/celestial.git",
-        &args.celestial_branch,
-        &celestial_jar_path,
-        &jdk,
-    )
-    .await
+    let build_context = BuildContext {
+        client: &client,
+        downloads: &downloads,
+        data_dir: &base_dir,
+        jdk: &jdk,
+        gradle_settings: &config.gradle,
+        timeout: config.build.timeout(),
+        no_build_cache: false,
+        cancel: &cancel,
+    };
+
+    if let Some(Command::VerifyBuild {
+        component,
+        commit,
+        reference,
+    }) = &args.command
     {
-        Ok(_) => (),
-        Err(err) => {
-            log_backtrace!("Failed to update Celestial! {}", err);
-            process::exit(1);
+        let component = match component {
+            ComponentName::Celestial => &celestial,
+            ComponentName::BrowserDebugger => &debugger,
+        };
+        let diff = verify_build(
+            &build_context,
+            component,
+            commit.as_deref(),
+            reference.as_deref(),
+        )
+        .await?;
+        if diff.is_empty() {
+            info!("{} was built reproducibly", component.name);
+            return Ok(());
+        }
+        for entry in &diff.differing {
+            error!("Differs: {entry}");
+        }
+        for entry in &diff.only_in_first {
+            error!("Only in the first build: {entry}");
+        }
+        let second = if reference.is_some() {
+            "the reference"
+        } else {
+            "the second build"
+        };
+        for entry in &diff.only_in_second {
+            error!("Only in {second}: {entry}");
         }
+        error!("{} is not reproducible", component.name);
+        process::exit(1);
     }
-    // Update Browser Debugger
-    if fs::try_exists(&debugger_jar_path).await? || is_first_run {
-        info!("Check update for Browser Debugger");
-        match check_update(
-            &base_dir.join("repositories").join("browser-debugger"),
-            "https://codeberg.org/earthsworth/BrowserDebugger.git",
-            &args.debugger_branch,
-            &debugger_jar_path,
-            &jdk,
-        )
-        .await
-        {
-            Ok(_) => (),
+
+    let is_first_run = !fs::try_exists(&celestial.jar_path).await?;
+
+    let mut components = vec![&celestial];
+    if fs::try_exists(&debugger.jar_path).await? || is_first_run {
+        components.push(&debugger);
+    } else {
+        info!("Skipped check update for Browser Debugger: user manually removed the agent");
+    }
+
+    // update the repositories at once, then build with a bounded number of parallel builds
+    let builds = Semaphore::new(config.build.max_parallel_builds.max(1));
+    let results = join_all(components.iter().map(|component| async {
+        info!("Check update for {}", component.name);
+        let (commit, changed) = tokio::select! {
+            biased;
+            _ = cancel.cancelled() => anyhow::bail!("Cancelled"),
+            result = update_repository(component) => result?,
+        };
+        if changed || !fs::try_exists(&component.jar_path).await? {
+            let _permit = tokio::select! {
+                biased;
+                _ = cancel.cancelled() => anyhow::bail!("Cancelled"),
+                permit = builds.acquire() => permit?,
+            };
+            build_component(&build_context, component, &commit).await?;
+        }
+        anyhow::Ok(())
+    }))
+    .await;
+
+    let mut failed = false;
+    for (component, result) in components.iter().zip(results) {
+        match result {
+            Ok(()) => info!("{} is up to date", component.name),
             Err(err) => {
-                log_backtrace!("Failed to update BrowserDebugger! {}", err);
-                process::exit(1);
+                log_backtrace!("Failed to update {}! {:#}", component.name, err);
+                failed = true;
             }
         }
-    } else {
-        info!("Skipped check update for Browser Debugger: user manually removed the agent");
+    }
+    if failed || cancel.is_cancelled() {
+        process::exit(1);
     }
 
     // spawn celestial
     info!("Spawning Celestial Launcher");
-    if let Ok(status) = spawn_jar(&jdk, &celestial_jar_path).await {
+    if let Ok(status) = spawn_jar(&jdk, &celestial.jar_path).await {
         if status.success() {
             info!("Celestial launcher terminated.");
         } else {
@@ -116,26 +280,120 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn run_cache_command(
+    action: &CacheAction,
+    data_dir: &Path,
+    settings: &GradleSettings,
+    download_cache: &DownloadCache,
+    components: &[&Component<'_>],
+) -> anyhow::Result<()> {
+    match action {
+        CacheAction::Info => {
+            let mut usage = cache_usage(data_dir, settings).await?;
+            usage.push(CacheUsage {
+                name: "Download cache",
+                path: download_cache.root.clone(),
+                size: dir_size(&download_cache.root).await?,
+            });
+            for cache in &usage {
+                info!(
+                    "{}: {} ({})",
+                    cache.name,
+                    format_size(cache.size),
+                    cache.path.display()
+                );
+            }
+            let total = usage.iter().map(|cache| cache.size).sum();
+            info!("Total: {}", format_size(total));
+        }
+        CacheAction::Prune { max_age_days } => {
+            let mut projects = Vec::new();
+            for component in components {
+                if let Some(wrapper) = WrapperProperties::load(&component.repo_path).await? {
+                    projects.push((component.repo_path.as_path(), wrapper));
+                }
+            }
+            let max_age = max_age_days.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+            let gradle_max_age = max_age.unwrap_or(DEFAULT_PRUNE_MAX_AGE);
+            let mut freed = prune_caches(data_dir, settings, &projects, gradle_max_age).await?;
+            freed += download_cache
+                .evict(download_cache.max_size, max_age.or(download_cache.max_age))
+                .await?;
+            info!("Freed {}", format_size(freed));
+        }
+    }
+    Ok(())
+}
+
+/// Download the artifacts of a manifest file and report the outcome of each one
+async fn run_download_command(
+    downloads: &DownloadManager,
+    manifest_path: &Path,
+    base_dir: &Path,
+) -> anyhow::Result<()> {
+    let content = fs::read_to_string(manifest_path)
+        .await
+        .with_context(|| format!("Failed to read manifest {}", manifest_path.display()))?;
+    let manifest: BatchManifest = toml::from_str(&content)
+        .with_context(|| format!("Failed to parse manifest {}", manifest_path.display()))?;
+
+    let results = download_batch(downloads, &manifest, base_dir).await;
+    let total = results.len();
+    let mut failed = 0;
+    for result in results {
+        match result.outcome {
+            Ok(source) => info!("OK {} (from {source})", result.dest.display()),
+            Err(err) => {
+                failed += 1;
+                error!(
+                    "FAILED {}: {:#}",
+                    result.dest.display(),
+                    anyhow::Error::from(err)
+                );
+            }
+        }
+    }
+    info!("Downloaded {} of {total} artifacts", total - failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Cancel `cancel` on Ctrl-C, so builds stop their processes and queued ones never start.
+///
+/// A second Ctrl-C exits right away, e.g. while a download is still finishing.
+fn cancel_on_ctrl_c(cancel: CancellationToken) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        warn!("Cancelling, press Ctrl-C again to exit immediately");
+        cancel.cancel();
+        if tokio::signal::ctrl_c().await.is_ok() {
+            process::exit(130);
+        }
+    });
+}
+
 async fn spawn_jar(java: &impl JdkTrait, jar_path: &Path) -> io::Result<ExitStatus> {
     let mut command = tokio::process::Command::new(java.java_executable());
     command.arg("-jar");
     command.arg(jar_path);
+    protect_stdout(&mut command);
 
     // spawn command
     let mut child = command.spawn()?;
     child.wait().await
 }
 
-async fn check_update(
-    repo_path: &Path,
-    repo: &str,
-    branch: &str,
-    emitted_jar_path: &Path,
-    jdk: &impl JdkTrait,
-) -> anyhow::Result<()> {
-    let branch = branch.to_string();
-    let repo_path = repo_path.to_owned();
-    let repo = repo.to_string();
+/// Clone or fast-forward the component's repository.
+///
+/// Returns the checked out commit and whether it changed.
+async fn update_repository(component: &Component<'_>) -> anyhow::Result<(String, bool)> {
+    let branch = component.branch.to_string();
+    let repo_path = component.repo_path.clone();
+    let repo = component.repository.to_string();
     // (repo, should (re-)build jar)
     let (repo, should_build): (Repository, bool) = tokio::task::spawn_blocking(move || {
         // TODO: checkout branch/commit
@@ -169,21 +427,8 @@ async fn check_update(
         Ok((repo, true))
     })
     .await?
-    .map_err(|err| {
-        anyhow::Error::msg(format!(
-            "Failed to clone/open repository: {}",
-            err.to_string()
-        ))
-    })?;
-
-    let repo_path = repo.path().parent().unwrap();
-    let should_build = should_build || !fs::try_exists(emitted_jar_path).await?;
-
-    // build with gradle
-    if should_build {
-        info!("Building Celestial");
-        build_with_gradle(jdk, &repo_path, emitted_jar_path, "-fatjar").await?;
-    }
+    .map_err(|err| anyhow::Error::msg(format!("Failed to clone/open repository: {}", err)))?;
 
-    Ok(())
+    let commit = head_commit(&repo)?;
+    Ok((commit, should_build))
 }
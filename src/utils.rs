@@ -1,7 +1,14 @@
+pub mod archive;
+pub mod disk;
 pub mod download;
 pub mod git;
 pub mod hashing;
+pub mod http;
 pub mod logging;
+pub mod path_lock;
+pub mod process;
+pub mod properties;
+pub mod random;
 pub mod stream;
 pub mod tempfile_async;
 pub mod timestamp;
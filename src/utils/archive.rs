@@ -0,0 +1,116 @@
+use async_zip::error::ZipError;
+use async_zip::tokio::read::fs::ZipFileReader;
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+use tokio::fs;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("Failed to read archive")]
+    Zip(#[from] ZipError),
+
+    #[error("IO Error")]
+    Io(#[from] std::io::Error),
+
+    #[error("Archive entry escapes the target directory: {0}")]
+    UnsafeEntryPath(String),
+}
+
+/// Extract a zip archive into `target_dir`.
+///
+/// Unix permissions stored in the archive are restored, so launcher scripts stay executable.
+pub async fn extract_zip(zip_path: &Path, target_dir: &Path) -> Result<(), ArchiveError> {
+    let reader = ZipFileReader::new(zip_path).await?;
+
+    for (index, entry) in reader.file().entries().iter().enumerate() {
+        let entry_name = entry.filename().as_str()?;
+        let entry_path = target_dir.join(safe_entry_path(entry_name)?);
+
+        if entry.dir()? {
+            fs::create_dir_all(&entry_path).await?;
+            continue;
+        }
+
+        if let Some(parent) = entry_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let mut entry_reader = reader.reader_without_entry(index).await?.compat();
+        let mut file = fs::File::create(&entry_path).await?;
+        tokio::io::copy(&mut entry_reader, &mut file).await?;
+
+        #[cfg(unix)]
+        if let Some(mode) = entry.unix_permissions() {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = u32::from(mode) & 0o777;
+            if mode != 0 {
+                fs::set_permissions(&entry_path, std::fs::Permissions::from_mode(mode)).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Reject absolute paths and `..` components (zip slip)
+fn safe_entry_path(entry_name: &str) -> Result<PathBuf, ArchiveError> {
+    let path = Path::new(entry_name);
+    if path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        Ok(path.to_owned())
+    } else {
+        Err(ArchiveError::UnsafeEntryPath(entry_name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_zip::tokio::write::ZipFileWriter;
+    use async_zip::{Compression, ZipEntryBuilder};
+
+    async fn write_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let file = fs::File::create(path).await.unwrap();
+        let mut writer = ZipFileWriter::with_tokio(file);
+        for (name, data) in entries {
+            let entry = ZipEntryBuilder::new((*name).into(), Compression::Deflate);
+            writer.write_entry_whole(entry, data).await.unwrap();
+        }
+        writer.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_extract_zip() {
+        let dir = tempfile::tempdir().unwrap();
+        let zip_path = dir.path().join("test.zip");
+        write_zip(
+            &zip_path,
+            &[
+                ("gradle-1.0/", b""),
+                ("gradle-1.0/bin/gradle", b"#!/bin/sh"),
+            ],
+        )
+        .await;
+
+        let target = dir.path().join("out");
+        extract_zip(&zip_path, &target).await.unwrap();
+        assert_eq!(
+            fs::read(target.join("gradle-1.0/bin/gradle"))
+                .await
+                .unwrap(),
+            b"#!/bin/sh"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_extract_zip_rejects_escaping_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let zip_path = dir.path().join("evil.zip");
+        write_zip(&zip_path, &[("../evil.txt", b"evil")]).await;
+
+        let result = extract_zip(&zip_path, &dir.path().join("out")).await;
+        assert!(matches!(result, Err(ArchiveError::UnsafeEntryPath(_))));
+    }
+}
@@ -0,0 +1,126 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+
+/// Total size of the regular files below `path`, symlinks are not followed.
+///
+/// A missing path has a size of 0.
+pub async fn dir_size(path: &Path) -> io::Result<u64> {
+    if !fs::try_exists(path).await? {
+        return Ok(0);
+    }
+    let mut size = 0;
+    let mut pending = vec![path.to_owned()];
+    while let Some(dir) = pending.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                size += entry.metadata().await?.len();
+            }
+        }
+    }
+    Ok(size)
+}
+
+/// Remove a file or directory tree, returning the number of bytes freed
+pub async fn remove_path(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path).await?;
+    if metadata.is_dir() {
+        let size = dir_size(path).await?;
+        fs::remove_dir_all(path).await?;
+        Ok(size)
+    } else {
+        fs::remove_file(path).await?;
+        Ok(metadata.len())
+    }
+}
+
+/// Remove the files below `dir` that were not modified within `max_age`.
+///
+/// Returns the number of bytes freed.
+pub async fn remove_files_older_than(dir: &Path, max_age: Duration) -> io::Result<u64> {
+    if !fs::try_exists(dir).await? {
+        return Ok(0);
+    }
+    let cutoff = SystemTime::now()
+        .checked_sub(max_age)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    let mut freed = 0;
+    let mut pending: Vec<PathBuf> = vec![dir.to_owned()];
+    while let Some(dir) = pending.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                pending.push(entry.path());
+                continue;
+            }
+            let metadata = entry.metadata().await?;
+            if metadata.modified()? < cutoff {
+                fs::remove_file(entry.path()).await?;
+                freed += metadata.len();
+            }
+        }
+    }
+    Ok(freed)
+}
+
+/// Append `suffix` to the file name of `path`, e.g. `gradle.zip` -> `gradle.zip.part`
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Format a byte count for humans, e.g. `1.5 MiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[tokio::test]
+    async fn test_remove_files_older_than() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries = dir.path().join("entries");
+        std::fs::create_dir_all(&entries).unwrap();
+        std::fs::write(entries.join("fresh"), b"fresh").unwrap();
+        let old = std::fs::File::create(entries.join("old")).unwrap();
+        old.set_len(10).unwrap();
+        old.set_modified(SystemTime::now() - Duration::from_secs(3600))
+            .unwrap();
+        drop(old);
+
+        assert_eq!(dir_size(dir.path()).await.unwrap(), 15);
+        let freed = remove_files_older_than(dir.path(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(freed, 10);
+        assert!(entries.join("fresh").exists());
+        assert!(!entries.join("old").exists());
+    }
+}
@@ -1,22 +1,41 @@
+pub mod batch;
+pub mod budget;
+pub mod cache;
+pub mod conditional;
+pub mod manager;
+pub mod progress;
+pub mod retry;
+#[cfg(test)]
+pub mod test_server;
+
 use async_zip::error::ZipError;
-use digest::DynDigest;
 use futures_util::StreamExt;
-use log::error;
-use md5::Digest;
-use reqwest::Client;
-use std::{backtrace::Backtrace, ops::Range, path::PathBuf, sync::Arc};
+use futures_util::future::join_all;
+use log::{info, warn};
+use reqwest::header::{
+    ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, HeaderName, IF_RANGE, LAST_MODIFIED, RANGE,
+};
+use reqwest::{Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, SeekFrom},
+    ops::{Range, RangeInclusive},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use tokio::{
     fs::{self, File},
-    io::{AsyncWriteExt, BufReader},
-    sync::mpsc,
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::Mutex,
 };
 
 use thiserror::Error;
 
+use crate::utils::download::progress::{ChunkProgress, DownloadEvent, DownloadId};
+use crate::utils::download::retry::{RetryPolicy, parse_retry_after};
 use crate::utils::{
-    hashing::{Hash, HashingError},
-    stream::stream_write_and_calculate_hash,
-    tempfile_async,
+    disk::with_suffix,
+    hashing::{Hash, HashingError, compare_file_hash},
 };
 
 #[derive(Error, Debug)]
@@ -33,215 +52,1296 @@ pub enum DownloadError {
     #[error("IO Error")]
     Io(#[from] std::io::Error),
 
-    #[error("Max retries exceeded when requesting to URL {url} (max_retries = {max_retries})")]
-    MaxRetriesExceeded { url: String, max_retries: u32 },
+    #[error("Max retries exceeded when requesting to URL {url} (attempts = {attempts})")]
+    MaxRetriesExceeded {
+        url: String,
+        attempts: u32,
+        #[source]
+        source: Box<DownloadError>,
+    },
+
+    #[error("Server responded to {url} with {status}")]
+    Status {
+        url: String,
+        status: StatusCode,
+        /// The delay asked for with `Retry-After`
+        retry_after: Option<Duration>,
+    },
 
     #[error("Failed to create parent folders of the path {0}")]
     FailedCreateParentFolders(PathBuf),
+
+    #[error("Expected a partial response for bytes {range}, got {status}")]
+    RangeNotHonored { range: String, status: StatusCode },
+
+    #[error("Requested bytes {range}, got Content-Range {actual:?}")]
+    ContentRangeMismatch {
+        range: String,
+        actual: Option<String>,
+    },
+
+    #[error("Expected {expected} bytes, got {actual}")]
+    LengthMismatch { expected: u64, actual: u64 },
+
+    #[error("{url} announces {actual} bytes, expected {expected}")]
+    SizeMismatch {
+        url: String,
+        expected: u64,
+        actual: u64,
+    },
+
+    #[error("Failed to download chunk {chunk} (bytes {range}) of {url}")]
+    ChunkFailed {
+        url: String,
+        chunk: usize,
+        range: String,
+        #[source]
+        source: Box<DownloadError>,
+    },
+
+    #[error("All mirrors of {artifact} failed")]
+    MirrorsExhausted {
+        artifact: String,
+        #[source]
+        source: Box<DownloadError>,
+    },
+
+    #[error("No URL to download {0} from")]
+    NoMirrors(String),
+}
+
+/// One download of a URL, the id tells its [DownloadEvent]s apart from those of other
+/// downloads, even of the same URL
+#[derive(Debug, Clone, Copy)]
+struct Transfer<'a> {
+    id: DownloadId,
+    url: &'a str,
 }
 
+impl<'a> Transfer<'a> {
+    fn new(url: &'a str) -> Self {
+        Self {
+            id: progress::next_download_id(),
+            url,
+        }
+    }
+}
+
+/// Files up to this size are not worth splitting into ranges
+const MIN_PARALLEL_SIZE: u64 = 5120;
+
+/// Download `url` to `dest` with up to `concurrency` range requests at once.
+///
+/// The server must announce `Accept-Ranges: bytes` and the length of the file,
+/// otherwise (or if the file is small, or `HEAD` fails) this falls back to
+/// [download_single_thread].
+/// `<dest>.part` is preallocated and every chunk is written at its offset. Every chunk
+/// must come back as a `206` with the requested `Content-Range` and length. The `HEAD`
+/// request and failed chunks are retried according to `retry`, a chunk that fails permanently or runs out of
+/// attempts fails the whole download with [DownloadError::ChunkFailed].
+///
+/// Finished chunks are recorded in the [DownloadState], so a later attempt only fetches
+/// the missing ones, as long as the file did not change. `dest` only appears once the
+/// file is complete and matches `expected_file_hash`.
+///
+/// Requests and bandwidth count against the global [budget].
+/// Progress is reported as [DownloadEvent]s to the [progress] subscribers.
 pub async fn download_parallelly(
     client: &Client,
     url: &str,
-    file: &mut File,
+    dest: &Path,
     expected_file_hash: Option<&Hash>,
+    expected_size: Option<u64>,
     concurrency: usize,
-    max_retries: u32,
+    retry: &RetryPolicy,
 ) -> Result<(), DownloadError> {
-    // fetch file size
-    let res = client.head(url).send().await?;
-    let total_size: Option<usize> = res
-        .headers()
-        .get(reqwest::header::CONTENT_LENGTH)
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.parse().ok());
+    let transfer = Transfer::new(url);
+    let result = parallel_download(
+        client,
+        transfer,
+        dest,
+        expected_file_hash,
+        expected_size,
+        concurrency,
+        retry,
+    )
+    .await;
+    report_outcome(transfer, result)
+}
 
-    let Some(total_size) = total_size else {
-        // download in the single thread since Celestial cannot know the size of the file
-        return download_single_thread(client, url, file, expected_file_hash, max_retries).await;
+async fn parallel_download(
+    client: &Client,
+    transfer: Transfer<'_>,
+    dest: &Path,
+    expected_file_hash: Option<&Hash>,
+    expected_size: Option<u64>,
+    concurrency: usize,
+    retry: &RetryPolicy,
+) -> Result<u64, DownloadError> {
+    let url = transfer.url;
+    // fetch file size and range support
+    let response = match head(client, transfer, retry).await {
+        Ok(response) => response,
+        Err(err) => {
+            // some servers reject HEAD, a plain GET may still work
+            warn!("HEAD request to {url} failed, downloading in one request: {err}");
+            return single_thread_download(
+                client,
+                transfer,
+                dest,
+                expected_file_hash,
+                expected_size,
+                retry,
+            )
+            .await;
+        }
     };
+    let announced_size: Option<u64> =
+        header_value(&response, CONTENT_LENGTH).and_then(|value| value.parse().ok());
+    check_size(url, expected_size, announced_size)?;
+    let total_size = announced_size.filter(|size| *size > MIN_PARALLEL_SIZE);
+    let accepts_ranges = header_value(&response, ACCEPT_RANGES)
+        .is_some_and(|value| value.eq_ignore_ascii_case("bytes"));
 
-    if total_size <= 5120 {
-        // file too small, do parallel download is expensize
-        return download_single_thread(client, url, file, expected_file_hash, max_retries).await;
-    }
-
-    let concurrency = if concurrency >= total_size {
-        concurrency / 2
-    } else {
-        concurrency
+    let Some(total_size) = total_size.filter(|_| accepts_ranges) else {
+        // unknown size, no range support or too small to be worth it
+        return single_thread_download(
+            client,
+            transfer,
+            dest,
+            expected_file_hash,
+            expected_size,
+            retry,
+        )
+        .await;
     };
 
-    let single_chunk_max_size = total_size / concurrency;
-    // split chunks
-    let mut chunk_ranges: Vec<Range<usize>> = Vec::new();
-    let mut counter: usize = 0;
-    loop {
-        let chunk_start = counter * single_chunk_max_size;
-        let chunk_end = (counter + 1) * single_chunk_max_size - 1;
+    // chunks must all come from the same version of the file
+    let mut state = DownloadState::new(url);
+    state.etag = header_value(&response, ETAG);
+    state.last_modified = header_value(&response, LAST_MODIFIED);
 
-        // chunk the border
-        let chunk_end = if total_size > chunk_end {
-            chunk_end
-        } else {
-            // EOF
-            total_size
-        };
+    create_parent_dirs(dest).await?;
+    let part_path = with_suffix(dest, ".part");
+    let state_path = with_suffix(dest, ".part.json");
+    let resumable = match DownloadState::load(&state_path).await {
+        Some(previous)
+            if previous.url == state.url
+                && previous.etag == state.etag
+                && previous.last_modified == state.last_modified
+                && state.if_range_validator().is_some() =>
+        {
+            fs::metadata(&part_path)
+                .await
+                .is_ok_and(|metadata| metadata.len() == total_size)
+                .then_some(previous)
+        }
+        _ => None,
+    };
+    let state = match resumable {
+        Some(previous) => previous,
+        None => {
+            File::create(&part_path).await?.set_len(total_size).await?;
+            state
+        }
+    };
+    state.save(&state_path).await?;
 
-        // add chunk
-        chunk_ranges.push(chunk_start..chunk_end);
-
-        if chunk_end == total_size {
-            // the last chunk
-            break;
-        }
-        counter += 1;
-    }
-
-    let client = Arc::new(client.clone());
-
-    // order, file
-    let (tx, mut rx) = mpsc::channel(20);
-
-    // start download tasks
-    for (chunk_num, chunk_range) in chunk_ranges.into_iter().enumerate() {
-        let client = Arc::clone(&client);
-        let url = url;
-        let url = url.to_string();
-        let tx = tx.clone();
-        tokio::spawn(async move {
-            for _retry_count in 1..max_retries {
-                let result: anyhow::Result<()> = async {
-                    // create temp file
-                    let (mut chunk_file_handle, chunk_file_path) =
-                        tempfile_async::tempfile().await?;
-
-                    let range = format!("bytes={}-{}", chunk_range.start, chunk_range.end);
-                    // download chunk
-                    let mut stream = client
-                        .get(&url)
-                        .header("Range", range)
-                        .send()
-                        .await?
-                        .bytes_stream();
-
-                    // write stream to chunk_file
-                    while let Some(chunk) = stream.next().await {
-                        let chunk = chunk?;
-                        chunk_file_handle.write_all(&chunk).await?;
-                    }
-
-                    // now this chunk is download successfully
-                    // add chunk_file_handle and path to completed files (with order)
-                    tx.send((chunk_num, chunk_file_handle, chunk_file_path))
-                        .await?;
-                    Ok(())
-                }
-                .await;
+    let validator = state.if_range_validator().map(String::from);
+    let chunks = chunk_ranges(total_size, concurrency);
+    progress::emit(DownloadEvent::Started {
+        id: transfer.id,
+        url: url.to_string(),
+        dest: dest.to_owned(),
+        total: Some(total_size),
+        chunks: chunks.len(),
+    });
+    let mut pending: Vec<(usize, RangeInclusive<u64>)> = Vec::new();
+    for (index, range) in chunks.into_iter().enumerate() {
+        if state.covers(&range) {
+            let length = range_length(&range);
+            ChunkProgress::new(transfer.id, url, index, length, Some(length));
+        } else {
+            pending.push((index, range));
+        }
+    }
+    let state = Mutex::new(state);
 
-                if let Ok(()) = result {
-                    // download successfully
-                    break;
-                }
-            }
-        });
+    // let the other chunks finish on failure, so the next attempt can skip them
+    let results = join_all(pending.into_iter().map(|(index, range)| {
+        let (part_path, state_path) = (&part_path, &state_path);
+        let (validator, state) = (validator.as_deref(), &state);
+        async move {
+            download_chunk_with_retries(
+                client, transfer, validator, index, &range, part_path, retry,
+            )
+            .await?;
+            let mut state = state.lock().await;
+            state.add_completed(&range);
+            state.save(state_path).await?;
+            Ok::<_, DownloadError>(())
+        }
+    }))
+    .await;
+    if let Some(err) = results.into_iter().find_map(Result::err) {
+        return Err(err);
     }
 
-    let mut completed_tasks = Vec::new();
+    // hash the whole file in one pass, chunks finish in any order
+    if let Some(expected_file_hash) = expected_file_hash {
+        if let Err(err) = compare_file_hash(&part_path, expected_file_hash).await {
+            fs::remove_file(&part_path).await?;
+            fs::remove_file(&state_path).await?;
+            return Err(DownloadError::Hashing(err));
+        }
+        report_verified(transfer, expected_file_hash);
+    }
+    fs::rename(&part_path, dest).await?;
+    fs::remove_file(&state_path).await?;
+    Ok(total_size)
+}
 
-    while let Some(completed_task) = rx.recv().await {
-        // add to vec
-        completed_tasks.push(completed_task);
+/// Send a `HEAD` request, retrying transient failures according to `retry`
+async fn head(
+    client: &Client,
+    transfer: Transfer<'_>,
+    retry: &RetryPolicy,
+) -> Result<Response, DownloadError> {
+    let url = transfer.url;
+    let mut attempt = 1;
+    loop {
+        let result = async {
+            let _permit = budget::acquire_request().await;
+            check_status(client.head(url).send().await?)
+        }
+        .await;
+        let err = match result {
+            Ok(response) => return Ok(response),
+            Err(err) => err,
+        };
+        if !retry.should_retry(attempt, &err) {
+            return Err(err);
+        }
+        warn!(
+            "HEAD request to {url} failed (attempt {attempt}/{}): {err}",
+            retry.max_attempts
+        );
+        progress::emit(DownloadEvent::Retry {
+            id: transfer.id,
+            url: url.to_string(),
+            chunk: None,
+            attempt,
+            error: err.to_string(),
+        });
+        tokio::time::sleep(retry.delay(attempt, &err)).await;
+        attempt += 1;
     }
+}
 
-    // sort completed tasks
-    completed_tasks.sort_by(|task_a, task_b| task_a.0.cmp(&task_b.0));
+/// Split `total` bytes into at most `count` contiguous inclusive ranges
+fn chunk_ranges(total: u64, count: usize) -> Vec<RangeInclusive<u64>> {
+    if total == 0 {
+        return Vec::new();
+    }
+    let count = (count.max(1) as u64).min(total);
+    let chunk_size = total.div_ceil(count);
+    (0..total)
+        .step_by(chunk_size as usize)
+        .map(|start| start..=(start + chunk_size).min(total) - 1)
+        .collect()
+}
 
-    let mut hasher = expected_file_hash.map(|hash| hash.create_hasher());
+async fn download_chunk_with_retries(
+    client: &Client,
+    transfer: Transfer<'_>,
+    validator: Option<&str>,
+    index: usize,
+    range: &RangeInclusive<u64>,
+    part_path: &Path,
+    retry: &RetryPolicy,
+) -> Result<(), DownloadError> {
+    let url = transfer.url;
+    let mut attempt = 1;
+    loop {
+        let err = match download_chunk(client, transfer, validator, index, range, part_path).await {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        warn!(
+            "Failed to download chunk {index} of {url} (attempt {attempt}/{}): {err}",
+            retry.max_attempts
+        );
+        if !retry.should_retry(attempt, &err) {
+            return Err(DownloadError::ChunkFailed {
+                url: url.to_string(),
+                chunk: index,
+                range: format!("{}-{}", range.start(), range.end()),
+                source: Box::new(err),
+            });
+        }
+        progress::emit(DownloadEvent::Retry {
+            id: transfer.id,
+            url: url.to_string(),
+            chunk: Some(index),
+            attempt,
+            error: err.to_string(),
+        });
+        tokio::time::sleep(retry.delay(attempt, &err)).await;
+        attempt += 1;
+    }
+}
 
-    // join chunks
-    for (_, chunk_file, chunk_path) in completed_tasks.into_iter() {
-        let mut reader = BufReader::new(chunk_file);
+/// Download one inclusive byte range of `url` into the same range of `part_path`
+async fn download_chunk(
+    client: &Client,
+    transfer: Transfer<'_>,
+    validator: Option<&str>,
+    index: usize,
+    range: &RangeInclusive<u64>,
+    part_path: &Path,
+) -> Result<(), DownloadError> {
+    let url = transfer.url;
+    let requested = format!("{}-{}", range.start(), range.end());
+    let _permit = budget::acquire_request().await;
+    let mut request = client.get(url).header(RANGE, format!("bytes={requested}"));
+    if let Some(validator) = validator {
+        request = request.header(IF_RANGE, validator);
+    }
+    let response = check_status(request.send().await?)?;
 
-        stream_write_and_calculate_hash(&mut reader, file, &mut hasher.as_mut()).await?;
-        // This chunk was dumped into the target file
-        // so delete it
-        fs::remove_file(chunk_path).await?;
+    // a 200 means the server ignored the range, or the file changed since we asked for its size
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(DownloadError::RangeNotHonored {
+            range: requested,
+            status: response.status(),
+        });
+    }
+    let content_range = header_value(&response, CONTENT_RANGE);
+    if content_range
+        .as_deref()
+        .and_then(parse_content_range)
+        .is_none_or(|(start, end)| start != *range.start() || end != *range.end())
+    {
+        return Err(DownloadError::ContentRangeMismatch {
+            range: requested,
+            actual: content_range,
+        });
     }
 
-    // verify hash
-    if let Some(hasher) = hasher {
-        let actual_hash = hex::encode(hasher.finalize());
-        let expected_hash = expected_file_hash.unwrap();
-        if actual_hash != expected_hash.value() {
-            return Err(DownloadError::Hashing(HashingError::HashNotMatch {
-                expected_hash: expected_hash.to_owned(),
-                actual_hash: actual_hash,
-            }));
-        }
+    let mut file = fs::OpenOptions::new().write(true).open(part_path).await?;
+    file.seek(SeekFrom::Start(*range.start())).await?;
+    let expected = range_length(range);
+    let mut progress = ChunkProgress::new(transfer.id, url, index, 0, Some(expected));
+    let mut written: u64 = 0;
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        budget::throttle(chunk.len()).await;
+        file.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+        progress.advance(chunk.len() as u64);
     }
+    file.flush().await?;
 
+    if written != expected {
+        return Err(DownloadError::LengthMismatch {
+            expected,
+            actual: written,
+        });
+    }
     Ok(())
 }
 
+/// Download `url` to `dest` in a single request, resuming an interrupted download.
+///
+/// Data is written to `<dest>.part`, next to a `<dest>.part.json` [DownloadState].
+/// After a failure, or on the next run, the download continues from the end of the
+/// `.part` file with `Range`/`If-Range`. If the server ignores the range or the file
+/// changed meanwhile, it starts over. Transient failures are retried according to
+/// `retry`, permanent ones like a `404` or a hash mismatch fail right away.
+/// `dest` only appears once the file is complete and matches `file_hash`.
+///
+/// Requests and bandwidth count against the global [budget].
+/// Progress is reported as [DownloadEvent]s to the [progress] subscribers.
 pub async fn download_single_thread(
     client: &Client,
     url: &str,
-    file: &mut File,
+    dest: &Path,
     file_hash: Option<&Hash>,
-    max_retries: u32,
+    expected_size: Option<u64>,
+    retry: &RetryPolicy,
 ) -> Result<(), DownloadError> {
-    for retry_count in 1..=max_retries {
-        // get file
-        let result: anyhow::Result<()> = {
-            let mut stream = client.get(url).send().await?.bytes_stream();
-
-            let mut hasher = file_hash.map(|hash| hash.create_hasher());
-            // stream write file
-            while let Some(chunk) = stream.next().await {
-                let chunk = chunk?;
-                file.write_all(&chunk).await?;
-
-                // update hasher if possible
-                hasher
-                    .iter_mut()
-                    .next()
-                    .map(|hasher| hasher.update(&chunk))
-                    .unwrap_or(());
-            }
-            // check hash
+    let transfer = Transfer::new(url);
+    let result =
+        single_thread_download(client, transfer, dest, file_hash, expected_size, retry).await;
+    report_outcome(transfer, result)
+}
+
+async fn single_thread_download(
+    client: &Client,
+    transfer: Transfer<'_>,
+    dest: &Path,
+    file_hash: Option<&Hash>,
+    expected_size: Option<u64>,
+    retry: &RetryPolicy,
+) -> Result<u64, DownloadError> {
+    let url = transfer.url;
+    let part_path = with_suffix(dest, ".part");
+    let state_path = with_suffix(dest, ".part.json");
+    create_parent_dirs(dest).await?;
+
+    let mut state = match DownloadState::load(&state_path).await {
+        Some(state) if state.url == url => state,
+        _ => DownloadState::new(url),
+    };
+    progress::emit(DownloadEvent::Started {
+        id: transfer.id,
+        url: url.to_string(),
+        dest: dest.to_owned(),
+        total: None,
+        chunks: 1,
+    });
+
+    let mut attempt = 1;
+    loop {
+        let result = async {
+            let size = download_resuming(
+                client,
+                transfer,
+                &part_path,
+                &state_path,
+                &mut state,
+                expected_size,
+            )
+            .await?;
             if let Some(file_hash) = file_hash {
-                // compare hash
-                let hasher = hasher.unwrap();
-                let actual_hash = hex::encode(hasher.finalize());
-                if file_hash.value() != actual_hash {
-                    return Err(DownloadError::Hashing(HashingError::HashNotMatch {
-                        expected_hash: file_hash.to_owned(),
-                        actual_hash,
-                    }));
+                if let Err(err) = compare_file_hash(&part_path, file_hash).await {
+                    // the data is bad, resuming from it would not help
+                    state.completed.clear();
+                    state.save(&state_path).await?;
+                    return Err(DownloadError::Hashing(err));
                 }
+                report_verified(transfer, file_hash);
             }
-            Ok(())
+            Ok(size)
+        }
+        .await;
+
+        let err = match result {
+            Ok(size) => {
+                fs::rename(&part_path, dest).await?;
+                fs::remove_file(&state_path).await?;
+                return Ok(size);
+            }
+            Err(err) => err,
         };
+        warn!(
+            "Failed to download {url} (attempt {attempt}/{}): {err}",
+            retry.max_attempts
+        );
+        if !err.is_transient() {
+            return Err(err);
+        }
+        if !retry.should_retry(attempt, &err) {
+            return Err(DownloadError::MaxRetriesExceeded {
+                url: url.to_string(),
+                attempts: attempt,
+                source: Box::new(err),
+            });
+        }
+        progress::emit(DownloadEvent::Retry {
+            id: transfer.id,
+            url: url.to_string(),
+            chunk: None,
+            attempt,
+            error: err.to_string(),
+        });
+        tokio::time::sleep(retry.delay(attempt, &err)).await;
+        attempt += 1;
+    }
+}
 
-        if let Err(err) = result {
-            error!(
-                "Error happened when download file (retry {retry_count}/{max_retries}): {err}, \n{}",
-                Backtrace::capture()
-            );
+/// One attempt of [download_single_thread], continuing after the bytes already in `part_path`
+async fn download_resuming(
+    client: &Client,
+    transfer: Transfer<'_>,
+    part_path: &Path,
+    state_path: &Path,
+    state: &mut DownloadState,
+    expected_size: Option<u64>,
+) -> Result<u64, DownloadError> {
+    let url = transfer.url;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(part_path)
+        .await?;
+    let _permit = budget::acquire_request().await;
+    // the state may be ahead of the file if we were killed before flushing
+    let resume_from = state.resume_offset().min(file.metadata().await?.len());
+
+    let mut requested = resume_from;
+    let mut response = send_range_request(client, state, requested).await?;
+    if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // the file shrank, or our state is bogus
+        requested = 0;
+        response = send_range_request(client, state, requested).await?;
+    }
+    let mut response = check_status(response)?;
+    if response.status() == StatusCode::PARTIAL_CONTENT
+        && content_range_start(&response) != Some(requested)
+    {
+        // not the bytes we asked for, start over without a range
+        warn!(
+            "Got Content-Range {:?} for bytes {requested}- of {}, restarting",
+            header_value(&response, CONTENT_RANGE),
+            state.url
+        );
+        requested = 0;
+        response = check_status(send_range_request(client, state, requested).await?)?;
+    }
+
+    let offset = match response.status() {
+        StatusCode::PARTIAL_CONTENT if content_range_start(&response) == Some(requested) => {
+            requested
+        }
+        StatusCode::PARTIAL_CONTENT => {
+            return Err(DownloadError::ContentRangeMismatch {
+                range: format!("{requested}-"),
+                actual: header_value(&response, CONTENT_RANGE),
+            });
+        }
+        // a full response, the server ignored the range or the file changed
+        _ => 0,
+    };
+    let total = response.content_length().map(|length| offset + length);
+    check_size(url, expected_size, total)?;
+    if resume_from > 0 {
+        if offset > 0 {
+            info!("Resuming download of {} at byte {offset}", state.url);
         } else {
-            // operation success
-            return Ok(());
+            info!("Restarting download of {}, cannot resume", state.url);
         }
     }
-    error!(
-        "Failed to download file with hash {}: Max retries exceeded",
-        file_hash
-            .map(|hash| hash.value())
-            .unwrap_or("<unknown hash>")
-    );
-    Err(DownloadError::MaxRetriesExceeded {
-        url: url.to_string(),
-        max_retries,
-    })
+
+    state.etag = header_value(&response, ETAG);
+    state.last_modified = header_value(&response, LAST_MODIFIED);
+    state.set_completed(offset);
+    state.save(state_path).await?;
+
+    file.set_len(offset).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let mut progress = ChunkProgress::new(transfer.id, url, 0, offset, total);
+    let mut written = offset;
+    let mut last_saved = offset;
+    let mut stream = response.bytes_stream();
+    let result = async {
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            budget::throttle(chunk.len()).await;
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+            progress.advance(chunk.len() as u64);
+
+            if written - last_saved >= STATE_SAVE_INTERVAL {
+                file.flush().await?;
+                state.set_completed(written);
+                state.save(state_path).await?;
+                last_saved = written;
+            }
+        }
+        Ok::<_, DownloadError>(())
+    }
+    .await;
+
+    // keep what we have for the next attempt, even on failure
+    file.flush().await?;
+    state.set_completed(written);
+    state.save(state_path).await?;
+    result.map(|()| written)
+}
+
+/// Fail with [DownloadError::SizeMismatch] if the server announces a size other than `expected`
+fn check_size(
+    url: &str,
+    expected: Option<u64>,
+    announced: Option<u64>,
+) -> Result<(), DownloadError> {
+    match (expected, announced) {
+        (Some(expected), Some(actual)) if actual != expected => Err(DownloadError::SizeMismatch {
+            url: url.to_string(),
+            expected,
+            actual,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Tell the subscribers how a download of `size` bytes ended
+fn report_outcome(
+    transfer: Transfer<'_>,
+    result: Result<u64, DownloadError>,
+) -> Result<(), DownloadError> {
+    let event = match &result {
+        Ok(size) => DownloadEvent::Finished {
+            id: transfer.id,
+            url: transfer.url.to_string(),
+            size: *size,
+        },
+        Err(err) => DownloadEvent::Failed {
+            id: transfer.id,
+            url: transfer.url.to_string(),
+            error: err.to_string(),
+        },
+    };
+    progress::emit(event);
+    result.map(|_| ())
+}
+
+fn report_verified(transfer: Transfer<'_>, hash: &Hash) {
+    progress::emit(DownloadEvent::Verified {
+        id: transfer.id,
+        url: transfer.url.to_string(),
+        hash: hash.to_string(),
+    });
+}
+
+fn range_length(range: &RangeInclusive<u64>) -> u64 {
+    range.end() - range.start() + 1
+}
+
+/// Turn an error status into [DownloadError::Status], keeping the `Retry-After` delay
+fn check_status(response: Response) -> Result<Response, DownloadError> {
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        return Err(DownloadError::Status {
+            url: response.url().to_string(),
+            status,
+            retry_after: parse_retry_after(response.headers()),
+        });
+    }
+    Ok(response)
+}
+
+/// Request the resource, starting at `offset` if it is not 0
+async fn send_range_request(
+    client: &Client,
+    state: &DownloadState,
+    offset: u64,
+) -> Result<Response, DownloadError> {
+    let mut request = client.get(&state.url);
+    if offset > 0 {
+        request = request.header(RANGE, format!("bytes={offset}-"));
+        if let Some(validator) = state.if_range_validator() {
+            request = request.header(IF_RANGE, validator);
+        }
+    }
+    Ok(request.send().await?)
+}
+
+/// The first byte position of the response's `Content-Range`
+fn content_range_start(response: &Response) -> Option<u64> {
+    let value = header_value(response, CONTENT_RANGE)?;
+    parse_content_range(&value).map(|(start, _)| start)
+}
+
+/// Parse `bytes <start>-<end>/<total>` into the inclusive range
+fn parse_content_range(value: &str) -> Option<(u64, u64)> {
+    let (range, _total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    Some((start.trim().parse().ok()?, end.trim().parse().ok()?))
+}
+
+fn header_value(response: &Response, name: HeaderName) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+async fn create_parent_dirs(dest: &Path) -> Result<(), DownloadError> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .map_err(|_| DownloadError::FailedCreateParentFolders(parent.to_owned()))?;
+    }
+    Ok(())
+}
+
+/// Persist the resume state this often while downloading
+const STATE_SAVE_INTERVAL: u64 = 4 * 1024 * 1024;
+
+/// What is known about a partially downloaded file, stored next to its `.part` file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadState {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Byte ranges of the `.part` file that hold downloaded data, end exclusive
+    pub completed: Vec<Range<u64>>,
+}
+
+impl DownloadState {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            etag: None,
+            last_modified: None,
+            completed: Vec::new(),
+        }
+    }
+
+    /// Load a state file, a missing or unreadable one means starting over
+    pub async fn load(path: &Path) -> Option<Self> {
+        let content = fs::read(path).await.ok()?;
+        serde_json::from_slice(&content).ok()
+    }
+
+    pub async fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_vec(self)?).await
+    }
+
+    /// The length of the downloaded data at the start of the file
+    pub fn resume_offset(&self) -> u64 {
+        let mut offset = 0;
+        let mut ranges = self.completed.clone();
+        ranges.sort_by_key(|range| range.start);
+        for range in ranges {
+            if range.start > offset {
+                break;
+            }
+            offset = offset.max(range.end);
+        }
+        offset
+    }
+
+    /// Whether the inclusive `range` was downloaded already
+    fn covers(&self, range: &RangeInclusive<u64>) -> bool {
+        self.completed
+            .iter()
+            .any(|completed| completed.start <= *range.start() && *range.end() < completed.end)
+    }
+
+    fn add_completed(&mut self, range: &RangeInclusive<u64>) {
+        self.completed.push(*range.start()..range.end() + 1);
+    }
+
+    fn set_completed(&mut self, length: u64) {
+        self.completed.clear();
+        if length > 0 {
+            self.completed.push(0..length);
+        }
+    }
+
+    /// The validator sent in `If-Range`.
+    /// Weak ETags are not allowed there, the modification date is used instead.
+    fn if_range_validator(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_server::{Fault, TestServer, test_body, test_client};
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    fn sha256_of(data: &[u8]) -> Hash {
+        Hash::Sha256(hex::encode(Sha256::digest(data)))
+    }
+
+    fn fast_retry(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+            jitter: false,
+        }
+    }
+
+    #[test]
+    fn test_chunk_ranges() {
+        assert_eq!(chunk_ranges(10, 3), vec![0..=3, 4..=7, 8..=9]);
+        assert_eq!(chunk_ranges(3, 8), vec![0..=0, 1..=1, 2..=2]);
+        assert_eq!(chunk_ranges(100, 1), vec![0..=99]);
+        assert!(chunk_ranges(0, 4).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_download_parallelly() {
+        let body = test_body(100_000);
+        let server = TestServer::start(body.clone()).await;
+        // one chunk fails once and one is cut off, both must be retried
+        server.push_fault(Fault::Status(503));
+        server.push_fault(Fault::Truncate(100));
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.bin");
+
+        download_parallelly(
+            &test_client(),
+            &server.url(),
+            &dest,
+            Some(&sha256_of(&body)),
+            None,
+            4,
+            &fast_retry(3),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(&dest).unwrap(), body);
+        assert_eq!(server.get_requests(), 6);
+        // nothing but the file is left behind
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    /// Collects the events of one URL, other tests download at the same time
+    struct EventCollector {
+        url: String,
+        events: std::sync::Mutex<Vec<DownloadEvent>>,
+    }
+
+    impl progress::ProgressSubscriber for EventCollector {
+        fn on_event(&self, event: &DownloadEvent) {
+            if event.url() == self.url {
+                self.events.lock().unwrap().push(event.clone());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_reports_progress() {
+        let body = test_body(100_000);
+        let server = TestServer::start(body.clone()).await;
+        server.push_fault(Fault::Status(503));
+        let collector = std::sync::Arc::new(EventCollector {
+            url: server.url(),
+            events: Default::default(),
+        });
+        progress::subscribe(collector.clone());
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.bin");
+
+        download_parallelly(
+            &test_client(),
+            &server.url(),
+            &dest,
+            Some(&sha256_of(&body)),
+            None,
+            2,
+            &fast_retry(2),
+        )
+        .await
+        .unwrap();
+
+        let events = collector.events.lock().unwrap();
+        assert!(matches!(
+            events.first(),
+            Some(DownloadEvent::Started {
+                total: Some(100_000),
+                chunks: 2,
+                ..
+            })
+        ));
+        assert!(
+            events
+                .iter()
+                .any(|event| matches!(event, DownloadEvent::Retry { attempt: 1, .. }))
+        );
+        for chunk in 0..2 {
+            assert!(events.iter().any(|event| matches!(
+                event,
+                DownloadEvent::Progress { chunk: c, done: 50_000, .. } if *c == chunk
+            )));
+        }
+        assert!(matches!(
+            &events[events.len() - 2..],
+            [
+                DownloadEvent::Verified { .. },
+                DownloadEvent::Finished { size: 100_000, .. }
+            ]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_download_parallelly_names_failed_chunk() {
+        let server = TestServer::start(test_body(100_000)).await;
+        server.state.broken_offsets.lock().unwrap().insert(25_000);
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.bin");
+
+        let result = download_parallelly(
+            &test_client(),
+            &server.url(),
+            &dest,
+            None,
+            None,
+            4,
+            &fast_retry(2),
+        )
+        .await;
+
+        match result {
+            Err(DownloadError::ChunkFailed { chunk, range, .. }) => {
+                assert_eq!(chunk, 1);
+                assert_eq!(range, "25000-49999");
+            }
+            other => panic!("unexpected result {other:?}"),
+        }
+        assert!(!dest.exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_parallelly_resumes_missing_chunks() {
+        let body = test_body(100_000);
+        let server = TestServer::start(body.clone()).await;
+        server.state.broken_offsets.lock().unwrap().insert(50_000);
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.bin");
+        let hash = sha256_of(&body);
+
+        let retry = fast_retry(1);
+        let result = download_parallelly(
+            &test_client(),
+            &server.url(),
+            &dest,
+            Some(&hash),
+            None,
+            4,
+            &retry,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(DownloadError::ChunkFailed { chunk: 2, .. })
+        ));
+
+        server.state.broken_offsets.lock().unwrap().clear();
+        server.state.ranges.lock().unwrap().clear();
+        download_parallelly(
+            &test_client(),
+            &server.url(),
+            &dest,
+            Some(&hash),
+            None,
+            4,
+            &retry,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(&dest).unwrap(), body);
+        assert_eq!(
+            *server.state.ranges.lock().unwrap(),
+            vec![Some("bytes=50000-74999".to_string())]
+        );
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_download_parallelly_rejects_ignored_range() {
+        let server = TestServer::start(test_body(100_000)).await;
+        for _ in 0..4 {
+            server.push_fault(Fault::IgnoreRange);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.bin");
+
+        let result = download_parallelly(
+            &test_client(),
+            &server.url(),
+            &dest,
+            None,
+            None,
+            1,
+            &fast_retry(1),
+        )
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(DownloadError::ChunkFailed { source, .. })
+                if matches!(*source, DownloadError::RangeNotHonored { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_download_without_range_support() {
+        let body = test_body(100_000);
+        let server = TestServer::start_with(body.clone(), false).await;
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.bin");
+
+        download_parallelly(
+            &test_client(),
+            &server.url(),
+            &dest,
+            Some(&sha256_of(&body)),
+            None,
+            4,
+            &fast_retry(1),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(&dest).unwrap(), body);
+        assert_eq!(*server.state.ranges.lock().unwrap(), vec![None]);
+    }
+
+    #[tokio::test]
+    async fn test_download_falls_back_if_head_fails() {
+        let body = test_body(100_000);
+        let server = TestServer::start(body.clone()).await;
+        server.push_head_fault(Fault::Status(405));
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.bin");
+
+        download_parallelly(
+            &test_client(),
+            &server.url(),
+            &dest,
+            Some(&sha256_of(&body)),
+            None,
+            4,
+            &fast_retry(1),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(&dest).unwrap(), body);
+        assert_eq!(*server.state.ranges.lock().unwrap(), vec![None]);
+    }
+
+    #[tokio::test]
+    async fn test_download_retries_transient_status() {
+        let body = test_body(100_000);
+        let server = TestServer::start(body.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+
+        server.push_fault(Fault::Status(503));
+        let dest = dir.path().join("single.bin");
+        download_single_thread(
+            &test_client(),
+            &server.url(),
+            &dest,
+            Some(&sha256_of(&body)),
+            None,
+            &fast_retry(2),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), body);
+        assert_eq!(server.get_requests(), 2);
+
+        // the size is still known after HEAD is retried, so ranges are used
+        server.push_head_fault(Fault::Status(503));
+        server.state.ranges.lock().unwrap().clear();
+        let dest = dir.path().join("parallel.bin");
+        download_parallelly(
+            &test_client(),
+            &server.url(),
+            &dest,
+            Some(&sha256_of(&body)),
+            None,
+            2,
+            &fast_retry(2),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), body);
+        assert!(
+            server
+                .state
+                .ranges
+                .lock()
+                .unwrap()
+                .iter()
+                .all(Option::is_some)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_single_thread_resumes() {
+        let body = test_body(50_000);
+        let server = TestServer::start(body.clone()).await;
+        server.push_fault(Fault::Truncate(20_000));
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.bin");
+
+        download_single_thread(
+            &test_client(),
+            &server.url(),
+            &dest,
+            Some(&sha256_of(&body)),
+            None,
+            &fast_retry(2),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(&dest).unwrap(), body);
+        assert_eq!(
+            *server.state.ranges.lock().unwrap(),
+            vec![None, Some("bytes=20000-".to_string())]
+        );
+        assert!(!with_suffix(&dest, ".part.json").exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_single_thread_restarts_if_range_ignored() {
+        let body = test_body(50_000);
+        let server = TestServer::start(body.clone()).await;
+        server.push_fault(Fault::Truncate(20_000));
+        server.push_fault(Fault::IgnoreRange);
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.bin");
+
+        download_single_thread(
+            &test_client(),
+            &server.url(),
+            &dest,
+            Some(&sha256_of(&body)),
+            None,
+            &fast_retry(2),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(&dest).unwrap(), body);
+    }
+
+    #[tokio::test]
+    async fn test_download_single_thread_restarts_if_range_shifted() {
+        let body = test_body(50_000);
+        let server = TestServer::start(body.clone()).await;
+        server.push_fault(Fault::Truncate(20_000));
+        server.push_fault(Fault::ShiftRange(5_000));
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.bin");
+
+        download_single_thread(
+            &test_client(),
+            &server.url(),
+            &dest,
+            Some(&sha256_of(&body)),
+            None,
+            &fast_retry(2),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(&dest).unwrap(), body);
+        // the shifted range is dropped, and the file fetched again in full
+        assert_eq!(
+            *server.state.ranges.lock().unwrap(),
+            vec![None, Some("bytes=20000-".to_string()), None]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_checks_announced_size() {
+        let body = test_body(50_000);
+        let server = TestServer::start(body.clone()).await;
+        // without HEAD the size comes with the response to the GET request
+        server.push_head_fault(Fault::Status(405));
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.bin");
+
+        let result = download_parallelly(
+            &test_client(),
+            &server.url(),
+            &dest,
+            None,
+            Some(49_999),
+            4,
+            &fast_retry(3),
+        )
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(DownloadError::SizeMismatch {
+                expected: 49_999,
+                actual: 50_000,
+                ..
+            })
+        ));
+        assert_eq!(server.get_requests(), 1);
+        assert!(!dest.exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_does_not_retry_permanent_errors() {
+        let server = TestServer::start(test_body(100_000)).await;
+        server.push_fault(Fault::Status(404));
+        server.push_fault(Fault::Status(404));
+        let dir = tempfile::tempdir().unwrap();
+
+        let result = download_single_thread(
+            &test_client(),
+            &server.url(),
+            &dir.path().join("single.bin"),
+            None,
+            None,
+            &fast_retry(3),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(DownloadError::Status {
+                status: StatusCode::NOT_FOUND,
+                ..
+            })
+        ));
+        assert_eq!(server.get_requests(), 1);
+
+        let result = download_parallelly(
+            &test_client(),
+            &server.url(),
+            &dir.path().join("parallel.bin"),
+            None,
+            None,
+            1,
+            &fast_retry(3),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(DownloadError::ChunkFailed { source, .. })
+                if matches!(*source, DownloadError::Status { status: StatusCode::NOT_FOUND, .. })
+        ));
+        assert_eq!(server.get_requests(), 2);
+    }
+
+    #[test]
+    fn test_resume_offset() {
+        let mut state = DownloadState::new("https://example.com/file");
+        assert_eq!(state.resume_offset(), 0);
+
+        state.completed = vec![100..200, 0..50, 40..100, 300..400];
+        assert_eq!(state.resume_offset(), 200);
+
+        state.completed = vec![10..20, 20..30];
+        assert_eq!(state.resume_offset(), 0);
+    }
+
+    #[test]
+    fn test_if_range_validator() {
+        let mut state = DownloadState::new("https://example.com/file");
+        state.etag = Some("W/\"weak\"".to_string());
+        state.last_modified = Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string());
+        assert_eq!(
+            state.if_range_validator(),
+            Some("Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+
+        state.etag = Some("\"strong\"".to_string());
+        assert_eq!(state.if_range_validator(), Some("\"strong\""));
+    }
 }
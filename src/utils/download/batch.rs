@@ -0,0 +1,253 @@
+use crate::utils::archive::{ArchiveError, extract_zip};
+use crate::utils::download::DownloadError;
+use crate::utils::download::manager::{Artifact, DownloadManager, DownloadSource};
+use crate::utils::hashing::Hash;
+use futures_util::future::join_all;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+/// A list of artifacts to download, usually read from a TOML file:
+///
+/// ```toml
+/// [[artifact]]
+/// url = "https://example.com/agent.jar" # or mirrors = ["...", "..."]
+/// dest = "javaagents/agent.jar"
+/// hash = "sha256:..."                   # or sha256-<base64>, or bare hex
+/// size = 12345                          # optional
+/// extract_to = "assets"                 # optional, unpacks a zip
+/// ```
+///
+/// Paths must stay inside the base directory, and every `dest` must be unique.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(try_from = "ManifestSource")]
+pub struct BatchManifest {
+    pub artifacts: Vec<ManifestEntry>,
+}
+
+/// A manifest as written, before its paths are checked
+#[derive(Deserialize)]
+struct ManifestSource {
+    #[serde(default, rename = "artifact")]
+    artifacts: Vec<ManifestEntry>,
+}
+
+impl TryFrom<ManifestSource> for BatchManifest {
+    type Error = ManifestError;
+
+    fn try_from(source: ManifestSource) -> Result<Self, ManifestError> {
+        let mut dests = HashSet::new();
+        for entry in &source.artifacts {
+            if entry.dest.file_name().is_none() || !is_contained(&entry.dest) {
+                return Err(ManifestError::BadPath(entry.dest.clone()));
+            }
+            if let Some(extract_to) = &entry.extract_to
+                && !is_contained(extract_to)
+            {
+                return Err(ManifestError::BadPath(extract_to.clone()));
+            }
+            // `a.jar` and `./a.jar` are the same file
+            let dest: PathBuf = entry
+                .dest
+                .components()
+                .filter(|component| *component != Component::CurDir)
+                .collect();
+            if !dests.insert(dest) {
+                return Err(ManifestError::DuplicateDest(entry.dest.clone()));
+            }
+        }
+        Ok(Self {
+            artifacts: source.artifacts,
+        })
+    }
+}
+
+/// Whether `path` is relative and never leaves the directory it is joined to
+fn is_contained(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestEntry {
+    pub url: Option<String>,
+    /// Tried after `url`, in order
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    /// Relative to the batch base directory
+    pub dest: PathBuf,
+    pub hash: Hash,
+    /// The expected size in bytes, checked against `Content-Length` before downloading
+    pub size: Option<u64>,
+    /// Unpack the downloaded zip into this directory, relative to the base directory
+    pub extract_to: Option<PathBuf>,
+}
+
+#[derive(Error, Debug)]
+pub enum ManifestError {
+    #[error("Path {0} must be relative and must not contain `..`")]
+    BadPath(PathBuf),
+
+    #[error("More than one artifact is downloaded to {0}")]
+    DuplicateDest(PathBuf),
+}
+
+#[derive(Error, Debug)]
+pub enum BatchError {
+    #[error("No URL or mirrors given")]
+    NoUrl,
+
+    #[error(transparent)]
+    Download(#[from] DownloadError),
+
+    #[error("Failed to extract to {path}")]
+    Extract {
+        path: PathBuf,
+        #[source]
+        source: ArchiveError,
+    },
+}
+
+/// The outcome of one manifest entry
+#[derive(Debug)]
+pub struct BatchItemResult {
+    pub dest: PathBuf,
+    pub outcome: Result<DownloadSource, BatchError>,
+}
+
+/// Download every entry of `manifest` concurrently, paths are resolved against `base_dir`.
+///
+/// The global [budget](super::budget) bounds the requests in flight. A failing entry
+/// does not stop the others, every entry gets its own result, in manifest order.
+pub async fn download_batch(
+    manager: &DownloadManager,
+    manifest: &BatchManifest,
+    base_dir: &Path,
+) -> Vec<BatchItemResult> {
+    join_all(manifest.artifacts.iter().map(|entry| async move {
+        let dest = base_dir.join(&entry.dest);
+        let outcome = download_entry(manager, entry, &dest, base_dir).await;
+        BatchItemResult { dest, outcome }
+    }))
+    .await
+}
+
+async fn download_entry(
+    manager: &DownloadManager,
+    entry: &ManifestEntry,
+    dest: &Path,
+    base_dir: &Path,
+) -> Result<DownloadSource, BatchError> {
+    let mirrors: Vec<String> = entry
+        .url
+        .iter()
+        .chain(&entry.mirrors)
+        .flat_map(|url| manager.mirror_urls(url))
+        .collect();
+    if mirrors.is_empty() {
+        return Err(BatchError::NoUrl);
+    }
+    let artifact = Artifact {
+        name: entry.dest.to_string_lossy().into_owned(),
+        mirrors,
+        hash: Some(entry.hash.clone()),
+        size: entry.size,
+    };
+    let source = manager.download(&artifact, dest).await?;
+
+    if let Some(extract_to) = &entry.extract_to {
+        let path = base_dir.join(extract_to);
+        extract_zip(dest, &path)
+            .await
+            .map_err(|source| BatchError::Extract { path, source })?;
+    }
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::download::test_server::{TestServer, test_body, test_client};
+    use sha2::{Digest, Sha256};
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn test_download_batch() {
+        let body = test_body(1000);
+        let server = TestServer::start(body.clone()).await;
+        let hash = hex::encode(Sha256::digest(&body));
+        let manifest: BatchManifest = toml::from_str(&format!(
+            r#"
+            [[artifact]]
+            url = "{url}"
+            dest = "agents/a.jar"
+            hash = "sha256:{hash}"
+            size = 1000
+
+            [[artifact]]
+            mirrors = ["{url}"]
+            dest = "b.jar"
+            hash = "sha256:{hash}"
+            size = 999
+
+            [[artifact]]
+            dest = "c.jar"
+            hash = "sha256:{hash}"
+            "#,
+            url = server.url()
+        ))
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let manager = DownloadManager::new(test_client(), &BTreeMap::new());
+
+        let results = download_batch(&manager, &manifest, dir.path()).await;
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].dest, dir.path().join("agents/a.jar"));
+        assert!(matches!(
+            &results[0].outcome,
+            Ok(DownloadSource::Mirror(url)) if *url == server.url()
+        ));
+        assert_eq!(std::fs::read(&results[0].dest).unwrap(), body);
+        assert!(matches!(
+            &results[1].outcome,
+            Err(BatchError::Download(DownloadError::MirrorsExhausted { source, .. }))
+                if matches!(**source, DownloadError::SizeMismatch { expected: 999, actual: 1000, .. })
+        ));
+        assert!(!results[1].dest.exists());
+        // the wrong size was noticed before downloading
+        assert_eq!(server.get_requests(), 1);
+        assert!(matches!(results[2].outcome, Err(BatchError::NoUrl)));
+    }
+
+    #[test]
+    fn test_manifest_paths_are_checked() {
+        // (dest, extract_to) of every entry
+        let parse = |entries: &[(&str, &str)]| {
+            let manifest: String = entries
+                .iter()
+                .map(|(dest, extract_to)| {
+                    format!(
+                        "[[artifact]]\ndest = '{dest}'\nhash = '{}'\nextract_to = '{extract_to}'\n",
+                        "0".repeat(64)
+                    )
+                })
+                .collect();
+            toml::from_str::<BatchManifest>(&manifest)
+        };
+
+        assert!(parse(&[("a.jar", "assets/./a"), ("libs/a.jar", ".")]).is_ok());
+        for entries in [
+            &[("/etc/a.jar", "assets")][..],
+            &[("../a.jar", "assets")],
+            &[(".", "assets")],
+            &[("a.jar", "assets/../..")],
+            &[("a.jar", "/tmp")],
+            &[("a.jar", "a"), ("./a.jar", "b")],
+        ] {
+            assert!(parse(entries).is_err(), "accepted {entries:?}");
+        }
+    }
+}
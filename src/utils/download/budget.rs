@@ -0,0 +1,108 @@
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Limits shared by every download: in-flight requests and bandwidth.
+///
+/// Set once at startup with [configure], every request holds a permit from
+/// [acquire_request] and every received chunk of bytes passes [throttle].
+pub struct DownloadBudget {
+    requests: Option<Semaphore>,
+    bandwidth: Option<TokenBucket>,
+}
+
+impl DownloadBudget {
+    /// `None` means no limit
+    pub fn new(max_requests: Option<usize>, bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            requests: max_requests.map(|max| Semaphore::new(max.max(1))),
+            bandwidth: bytes_per_sec.map(TokenBucket::new),
+        }
+    }
+}
+
+static BUDGET: OnceLock<DownloadBudget> = OnceLock::new();
+
+/// Set the global budget, returns `false` if downloads already started without one
+pub fn configure(budget: DownloadBudget) -> bool {
+    BUDGET.set(budget).is_ok()
+}
+
+fn budget() -> &'static DownloadBudget {
+    BUDGET.get_or_init(|| DownloadBudget::new(None, None))
+}
+
+/// Wait for a free request slot, held until the permit is dropped
+pub async fn acquire_request() -> Option<SemaphorePermit<'static>> {
+    let requests = budget().requests.as_ref()?;
+    // the semaphore is never closed
+    requests.acquire().await.ok()
+}
+
+/// Account for `bytes` received, sleeping while over the bandwidth limit
+pub async fn throttle(bytes: usize) {
+    if let Some(bandwidth) = &budget().bandwidth {
+        let wait = bandwidth.reserve(bytes as u64, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// A token bucket refilled at `rate` bytes per second, holding up to one second of data.
+///
+/// Reservations may overdraw the bucket, later ones then wait for the debt to be
+/// repaid, so concurrent downloads share the rate in arrival order.
+struct TokenBucket {
+    rate: f64,
+    /// `(tokens, last refill)`
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        Self {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Take `bytes` tokens, returning how long to wait until they are covered
+    fn reserve(&self, bytes: u64, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let elapsed = now.saturating_duration_since(*last).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.rate).min(self.rate) - bytes as f64;
+        *last = now.max(*last);
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(1000);
+        let start = bucket.state.lock().unwrap().1;
+
+        // a full second of burst is available right away
+        assert_eq!(bucket.reserve(1000, start), Duration::ZERO);
+        assert_eq!(bucket.reserve(500, start), Duration::from_millis(500));
+        // the next caller queues behind the debt
+        assert_eq!(bucket.reserve(500, start), Duration::from_secs(1));
+
+        // refilled after the debt is repaid
+        let later = start + Duration::from_secs(2);
+        assert_eq!(bucket.reserve(1000, later), Duration::ZERO);
+        // never more than one second of burst
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(bucket.reserve(1500, much_later), Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,208 @@
+use crate::utils::hashing::{Hash, compare_file_hash};
+use crate::utils::tempfile_async::TempFile;
+use log::warn;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+
+/// Downloaded files stored by their hash at `<root>/<algorithm>/<digest>`.
+///
+/// Entries are verified again before use, a damaged entry is removed and reported
+/// as missing. Using an entry marks it as recently used for eviction.
+#[derive(Debug, Clone)]
+pub struct DownloadCache {
+    pub root: PathBuf,
+    /// Evict the least recently used entries once the cache grows beyond this
+    pub max_size: Option<u64>,
+    /// Evict entries not used within this time
+    pub max_age: Option<Duration>,
+}
+
+impl DownloadCache {
+    pub fn new(root: PathBuf, max_size: Option<u64>, max_age: Option<Duration>) -> Self {
+        Self {
+            root,
+            max_size,
+            max_age,
+        }
+    }
+
+    /// Where a file with `hash` is stored, `None` if the digest is not plain hex
+    pub fn entry_path(&self, hash: &Hash) -> Option<PathBuf> {
+        let digest = hash.value();
+        // the digest may come from config files, keep it from escaping the cache
+        if digest.is_empty() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(
+            self.root
+                .join(hash.hash_type().to_lowercase())
+                .join(digest.to_lowercase()),
+        )
+    }
+
+    /// Copy the entry for `hash` to `dest`, returning whether there was a valid one
+    pub async fn fetch(&self, hash: &Hash, dest: &Path) -> io::Result<bool> {
+        let Some(entry) = self.entry_path(hash) else {
+            return Ok(false);
+        };
+        if !fs::try_exists(&entry).await? {
+            return Ok(false);
+        }
+        if let Err(err) = compare_file_hash(&entry, hash).await {
+            warn!("Discard damaged download cache entry: {err}");
+            fs::remove_file(&entry).await?;
+            return Ok(false);
+        }
+        touch(&entry).await?;
+
+        let dest_dir = dest.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(dest_dir).await?;
+        let mut file = TempFile::new_in(dest_dir).await?;
+        let mut entry_file = fs::File::open(&entry).await?;
+        tokio::io::copy(&mut entry_file, &mut *file).await?;
+        file.persist(dest).await?;
+        Ok(true)
+    }
+
+    /// Store a copy of `file`, which must match `hash`, then evict old entries
+    pub async fn insert(&self, hash: &Hash, file: &Path) -> io::Result<()> {
+        let Some(entry) = self.entry_path(hash) else {
+            return Ok(());
+        };
+        let entry_dir = entry
+            .parent()
+            .expect("entries live in an algorithm directory");
+        fs::create_dir_all(entry_dir).await?;
+        let mut temp = TempFile::new_in(entry_dir).await?;
+        let mut source = fs::File::open(file).await?;
+        tokio::io::copy(&mut source, &mut *temp).await?;
+        temp.persist(&entry).await?;
+
+        self.evict(self.max_size, self.max_age).await?;
+        Ok(())
+    }
+
+    /// Remove entries unused within `max_age`, then the least recently used ones until
+    /// the cache fits into `max_size`. Returns the number of bytes freed.
+    pub async fn evict(&self, max_size: Option<u64>, max_age: Option<Duration>) -> io::Result<u64> {
+        let mut entries = self.entries().await?;
+        let cutoff = max_age.and_then(|max_age| SystemTime::now().checked_sub(max_age));
+        let mut freed = 0;
+
+        entries.sort_by_key(|(_, _, used)| *used);
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        for (path, len, used) in entries {
+            let expired = cutoff.is_some_and(|cutoff| used < cutoff);
+            let too_big = max_size.is_some_and(|max_size| size > max_size);
+            if !expired && !too_big {
+                continue;
+            }
+            fs::remove_file(&path).await?;
+            size -= len;
+            freed += len;
+        }
+        Ok(freed)
+    }
+
+    /// `(path, size, last used)` of every entry
+    async fn entries(&self) -> io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+        let mut entries = Vec::new();
+        if !fs::try_exists(&self.root).await? {
+            return Ok(entries);
+        }
+        let mut algorithms = fs::read_dir(&self.root).await?;
+        while let Some(algorithm) = algorithms.next_entry().await? {
+            if !algorithm.file_type().await?.is_dir() {
+                continue;
+            }
+            let mut files = fs::read_dir(algorithm.path()).await?;
+            while let Some(file) = files.next_entry().await? {
+                let path = file.path();
+                // skip entries still being written
+                if path.extension().is_some_and(|extension| extension == "tmp") {
+                    continue;
+                }
+                let metadata = file.metadata().await?;
+                if metadata.is_file() {
+                    entries.push((path, metadata.len(), metadata.modified()?));
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// Mark a file as used now
+async fn touch(path: &Path) -> io::Result<()> {
+    let file = fs::OpenOptions::new().write(true).open(path).await?;
+    file.into_std().await.set_modified(SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    fn sha256_of(data: &[u8]) -> Hash {
+        Hash::Sha256(hex::encode(Sha256::digest(data)))
+    }
+
+    #[tokio::test]
+    async fn test_download_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DownloadCache::new(dir.path().join("cache"), None, None);
+        let source = dir.path().join("source.bin");
+        std::fs::write(&source, b"content").unwrap();
+        let hash = sha256_of(b"content");
+
+        let dest = dir.path().join("out").join("dest.bin");
+        assert!(!cache.fetch(&hash, &dest).await.unwrap());
+        cache.insert(&hash, &source).await.unwrap();
+        let entry = cache.entry_path(&hash).unwrap();
+        assert!(entry.starts_with(dir.path().join("cache").join("sha256")));
+
+        assert!(cache.fetch(&hash, &dest).await.unwrap());
+        assert_eq!(std::fs::read(&dest).unwrap(), b"content");
+
+        // a damaged entry is dropped instead of served
+        std::fs::write(&entry, b"damaged").unwrap();
+        assert!(!cache.fetch(&hash, &dest).await.unwrap());
+        assert!(!entry.exists());
+
+        assert_eq!(
+            cache.entry_path(&Hash::Sha256("../../etc".to_string())),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_cache_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DownloadCache::new(dir.path().to_owned(), None, None);
+        let now = SystemTime::now();
+        let mut entries = Vec::new();
+        for (content, age) in [(b"old", 7200), (b"mid", 600), (b"new", 0)] {
+            let hash = sha256_of(content);
+            let entry = cache.entry_path(&hash).unwrap();
+            std::fs::create_dir_all(entry.parent().unwrap()).unwrap();
+            let file = std::fs::File::create(&entry).unwrap();
+            file.set_len(100).unwrap();
+            file.set_modified(now - Duration::from_secs(age)).unwrap();
+            entries.push(entry);
+        }
+
+        let freed = cache
+            .evict(None, Some(Duration::from_secs(3600)))
+            .await
+            .unwrap();
+        assert_eq!(freed, 100);
+        assert!(!entries[0].exists());
+
+        let freed = cache.evict(Some(150), None).await.unwrap();
+        assert_eq!(freed, 100);
+        assert!(!entries[1].exists());
+        assert!(entries[2].exists());
+    }
+}
@@ -0,0 +1,197 @@
+use crate::utils::download::{DownloadError, budget, check_status, header_value};
+use crate::utils::tempfile_async::TempFile;
+use log::{debug, warn};
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// How the body returned by [ConditionalCache::fetch] was obtained
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// The server sent a new body
+    Downloaded,
+    /// The server confirmed the cached body with `304 Not Modified`
+    NotModified,
+    /// The server could not be reached, the cached body may be outdated
+    Stale,
+}
+
+#[derive(Debug)]
+pub struct Fetched {
+    pub body: Vec<u8>,
+    pub freshness: Freshness,
+}
+
+impl Fetched {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// The validators of a cached body, stored next to it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CachedResponse {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Caches resources without a known hash, like version lists and checksum files.
+///
+/// Every body is stored with its `ETag` and `Last-Modified` in `dir`. Refreshing sends
+/// them as `If-None-Match` and `If-Modified-Since`, so an unchanged resource is not
+/// transferred again. If the server cannot be reached the cached body is used.
+#[derive(Debug, Clone)]
+pub struct ConditionalCache {
+    pub dir: PathBuf,
+}
+
+impl ConditionalCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Fetch `url`, revalidating the cached copy if there is one
+    pub async fn fetch(&self, client: &Client, url: &str) -> Result<Fetched, DownloadError> {
+        let (body_path, meta_path) = self.paths(url);
+        let cached = self.load(url, &body_path, &meta_path).await;
+
+        let mut request = client.get(url);
+        if let Some((meta, _)) = &cached {
+            if let Some(etag) = &meta.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &meta.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let result = async {
+            let _permit = budget::acquire_request().await;
+            let response = check_status(request.send().await?)?;
+            if response.status() == StatusCode::NOT_MODIFIED {
+                return Ok(None);
+            }
+            let meta = CachedResponse {
+                url: url.to_string(),
+                etag: header_value(&response, ETAG),
+                last_modified: header_value(&response, LAST_MODIFIED),
+            };
+            let body = response.bytes().await?.to_vec();
+            budget::throttle(body.len()).await;
+            Ok::<_, DownloadError>(Some((meta, body)))
+        }
+        .await;
+
+        match (result, cached) {
+            (Ok(Some((meta, body))), _) => {
+                if let Err(err) = self.store(&meta, &body, &body_path, &meta_path).await {
+                    warn!("Failed to cache {url}: {err}");
+                }
+                Ok(Fetched {
+                    body,
+                    freshness: Freshness::Downloaded,
+                })
+            }
+            (Ok(None), Some((_, body))) => Ok(Fetched {
+                body,
+                freshness: Freshness::NotModified,
+            }),
+            (Ok(None), None) => Err(DownloadError::Status {
+                url: url.to_string(),
+                status: StatusCode::NOT_MODIFIED,
+                retry_after: None,
+            }),
+            (Err(err), Some((_, body))) if err.is_transient() => {
+                warn!("Failed to refresh {url}, using the cached copy: {err}");
+                Ok(Fetched {
+                    body,
+                    freshness: Freshness::Stale,
+                })
+            }
+            (Err(err), _) => Err(err),
+        }
+    }
+
+    /// `(body, validators)` file paths, named after the URL's digest
+    fn paths(&self, url: &str) -> (PathBuf, PathBuf) {
+        let key = hex::encode(Sha256::digest(url.as_bytes()));
+        (
+            self.dir.join(format!("{key}.body")),
+            self.dir.join(format!("{key}.json")),
+        )
+    }
+
+    async fn load(
+        &self,
+        url: &str,
+        body_path: &Path,
+        meta_path: &Path,
+    ) -> Option<(CachedResponse, Vec<u8>)> {
+        let meta: CachedResponse = serde_json::from_slice(&fs::read(meta_path).await.ok()?).ok()?;
+        if meta.url != url {
+            return None;
+        }
+        let body = fs::read(body_path).await.ok()?;
+        Some((meta, body))
+    }
+
+    async fn store(
+        &self,
+        meta: &CachedResponse,
+        body: &[u8],
+        body_path: &Path,
+        meta_path: &Path,
+    ) -> Result<(), DownloadError> {
+        fs::create_dir_all(&self.dir).await?;
+        // drop the validators first, they must never describe another body
+        if fs::try_exists(meta_path).await? {
+            fs::remove_file(meta_path).await?;
+        }
+        let meta_json = serde_json::to_vec(meta).map_err(io::Error::from)?;
+        for (path, content) in [(body_path, body), (meta_path, &meta_json)] {
+            let mut file = TempFile::new_in(&self.dir).await?;
+            file.write_all(content).await?;
+            file.persist(path).await?;
+        }
+        debug!("Cached {} ({} bytes)", meta.url, body.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::download::test_server::{Fault, TestServer, test_body, test_client};
+
+    #[tokio::test]
+    async fn test_conditional_cache() {
+        let body = test_body(1000);
+        let server = TestServer::start(body.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ConditionalCache::new(dir.path().to_owned());
+        let client = test_client();
+
+        let fetched = cache.fetch(&client, &server.url()).await.unwrap();
+        assert_eq!(fetched.freshness, Freshness::Downloaded);
+        assert_eq!(fetched.body, body);
+
+        let fetched = cache.fetch(&client, &server.url()).await.unwrap();
+        assert_eq!(fetched.freshness, Freshness::NotModified);
+        assert_eq!(fetched.body, body);
+
+        server.push_fault(Fault::Status(503));
+        let fetched = cache.fetch(&client, &server.url()).await.unwrap();
+        assert_eq!(fetched.freshness, Freshness::Stale);
+        assert_eq!(fetched.body, body);
+
+        // a missing resource is not papered over
+        server.push_fault(Fault::Status(404));
+        assert!(cache.fetch(&client, &server.url()).await.is_err());
+    }
+}
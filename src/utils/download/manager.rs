@@ -0,0 +1,356 @@
+use crate::utils::download::cache::DownloadCache;
+use crate::utils::download::retry::RetryPolicy;
+use crate::utils::download::{DownloadError, download_parallelly};
+use crate::utils::hashing::Hash;
+use log::{info, warn};
+use reqwest::{Client, Url};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Hosts that failed within this time are tried after the others
+const FAILURE_COOLDOWN: Duration = Duration::from_secs(10 * 60);
+
+/// A file that can be fetched from any of its mirrors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artifact {
+    /// Used in logs and errors, e.g. the file name
+    pub name: String,
+    /// URLs serving the same file, in order of preference
+    pub mirrors: Vec<String>,
+    pub hash: Option<Hash>,
+    /// The expected size in bytes, mirrors announcing another size are skipped before
+    /// the transfer starts
+    pub size: Option<u64>,
+}
+
+/// Where a downloaded file came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadSource {
+    /// A valid copy was in the [DownloadCache]
+    Cache,
+    /// The URL of the mirror that served it
+    Mirror(String),
+}
+
+impl fmt::Display for DownloadSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadSource::Cache => write!(f, "download cache"),
+            DownloadSource::Mirror(url) => write!(f, "{url}"),
+        }
+    }
+}
+
+/// Downloads [Artifact]s, failing over between their mirrors.
+///
+/// Mirrors configured once per URL prefix apply to every artifact, and hosts that
+/// failed recently are moved to the back of the queue for all following downloads.
+/// Artifacts with a hash are served from and added to the [DownloadCache], if set.
+pub struct DownloadManager {
+    client: Client,
+    /// `(prefix, mirror bases)`, longest prefix first
+    mirrors: Vec<(String, Vec<String>)>,
+    pub concurrency: usize,
+    pub retry: RetryPolicy,
+    pub cache: Option<DownloadCache>,
+    failed_hosts: Mutex<HashMap<String, Instant>>,
+}
+
+impl DownloadManager {
+    /// `mirrors` maps URL prefixes to the bases of mirrors serving the same paths
+    pub fn new(client: Client, mirrors: &BTreeMap<String, Vec<String>>) -> Self {
+        let mut mirrors: Vec<(String, Vec<String>)> = mirrors
+            .iter()
+            .map(|(prefix, bases)| (prefix.trim_end_matches('/').to_string(), bases.clone()))
+            .collect();
+        mirrors.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Self {
+            client,
+            mirrors,
+            concurrency: 8,
+            retry: RetryPolicy::default(),
+            cache: None,
+            failed_hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// The configured mirrors of `url`, followed by `url` itself
+    pub fn mirror_urls(&self, url: &str) -> Vec<String> {
+        let mut urls: Vec<String> = self
+            .mirrors
+            .iter()
+            .find_map(|(prefix, bases)| {
+                let rest = url.strip_prefix(prefix.as_str())?;
+                (rest.is_empty() || rest.starts_with('/')).then(|| {
+                    bases
+                        .iter()
+                        .map(|base| format!("{}{rest}", base.trim_end_matches('/')))
+                        .collect()
+                })
+            })
+            .unwrap_or_default();
+        urls.push(url.to_string());
+        urls
+    }
+
+    /// Download `artifact` to `dest`, trying its mirrors until one serves a file
+    /// matching the hash. Returns where the file came from.
+    pub async fn download(
+        &self,
+        artifact: &Artifact,
+        dest: &Path,
+    ) -> Result<DownloadSource, DownloadError> {
+        let cache = self.cache.as_ref().zip(artifact.hash.as_ref());
+        if let Some((cache, hash)) = cache {
+            match cache.fetch(hash, dest).await {
+                Ok(true) => {
+                    info!("Using cached {}", artifact.name);
+                    return Ok(DownloadSource::Cache);
+                }
+                Ok(false) => {}
+                Err(err) => warn!(
+                    "Failed to read {} from the download cache: {err}",
+                    artifact.name
+                ),
+            }
+        }
+
+        let mut last_error = None;
+        for url in self.ordered_mirrors(artifact) {
+            match download_parallelly(
+                &self.client,
+                url,
+                dest,
+                artifact.hash.as_ref(),
+                artifact.size,
+                self.concurrency,
+                &self.retry,
+            )
+            .await
+            {
+                Ok(()) => {
+                    info!("Downloaded {} from {url}", artifact.name);
+                    if let Some((cache, hash)) = cache
+                        && let Err(err) = cache.insert(hash, dest).await
+                    {
+                        warn!(
+                            "Failed to add {} to the download cache: {err}",
+                            artifact.name
+                        );
+                    }
+                    return Ok(DownloadSource::Mirror(url.to_string()));
+                }
+                Err(err) => {
+                    warn!("Failed to download {} from {url}: {err}", artifact.name);
+                    if is_host_failure(&err)
+                        && let Some(host) = host_of(url)
+                    {
+                        self.failed_hosts
+                            .lock()
+                            .unwrap()
+                            .insert(host, Instant::now());
+                    }
+                    last_error = Some(err);
+                }
+            }
+        }
+        Err(match last_error {
+            Some(err) => DownloadError::MirrorsExhausted {
+                artifact: artifact.name.clone(),
+                source: Box::new(err),
+            },
+            None => DownloadError::NoMirrors(artifact.name.clone()),
+        })
+    }
+
+    /// The mirrors of `artifact`, healthy hosts first, then the ones that failed
+    /// longest ago
+    fn ordered_mirrors<'a>(&self, artifact: &'a Artifact) -> Vec<&'a str> {
+        let failed_hosts = self.failed_hosts.lock().unwrap();
+        let mut mirrors: Vec<(Option<Instant>, &str)> = artifact
+            .mirrors
+            .iter()
+            .map(|url| {
+                let failed_at = host_of(url)
+                    .and_then(|host| failed_hosts.get(&host).copied())
+                    .filter(|failed_at| failed_at.elapsed() < FAILURE_COOLDOWN);
+                (failed_at, url.as_str())
+            })
+            .collect();
+        // stable, so the preference order holds among equals
+        mirrors.sort_by_key(|(failed_at, _)| *failed_at);
+        mirrors.into_iter().map(|(_, url)| url).collect()
+    }
+}
+
+/// Whether `err` tells something about the host rather than the file or our disk:
+/// unreachable or overloaded servers, not a missing file or a hash mismatch
+fn is_host_failure(err: &DownloadError) -> bool {
+    match err {
+        DownloadError::Http(_) | DownloadError::Status { .. } => err.is_transient(),
+        DownloadError::ChunkFailed { source, .. }
+        | DownloadError::MaxRetriesExceeded { source, .. } => is_host_failure(source),
+        _ => false,
+    }
+}
+
+/// `host:port` of a URL, mirrors on one host usually fail together
+fn host_of(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    Some(format!(
+        "{}:{}",
+        url.host_str()?,
+        url.port_or_known_default()?
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::download::test_server::{Fault, TestServer, test_body, test_client};
+
+    fn no_retry() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn test_mirror_urls() {
+        let manager = DownloadManager::new(
+            test_client(),
+            &BTreeMap::from([
+                (
+                    "https://services.gradle.org/".to_string(),
+                    vec!["https://a.example/gradle/".to_string()],
+                ),
+                (
+                    "https://services.gradle.org/distributions".to_string(),
+                    vec![
+                        "https://b.example/gradle".to_string(),
+                        "https://c.example".to_string(),
+                    ],
+                ),
+            ]),
+        );
+
+        assert_eq!(
+            manager.mirror_urls("https://services.gradle.org/distributions/gradle-8.14-bin.zip"),
+            vec![
+                "https://b.example/gradle/gradle-8.14-bin.zip",
+                "https://c.example/gradle-8.14-bin.zip",
+                "https://services.gradle.org/distributions/gradle-8.14-bin.zip",
+            ]
+        );
+        assert_eq!(
+            manager.mirror_urls("https://services.gradle.org/versions/all"),
+            vec![
+                "https://a.example/gradle/versions/all",
+                "https://services.gradle.org/versions/all",
+            ]
+        );
+        assert_eq!(
+            manager.mirror_urls("https://services.gradle.org.evil/file"),
+            vec!["https://services.gradle.org.evil/file"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_fails_over() {
+        let body = test_body(1000);
+        let broken = TestServer::start(body.clone()).await;
+        broken.push_fault(Fault::Status(503));
+        let healthy = TestServer::start(body.clone()).await;
+        let mut manager = DownloadManager::new(test_client(), &BTreeMap::new());
+        manager.retry = no_retry();
+        let artifact = Artifact {
+            name: "file.bin".to_string(),
+            mirrors: vec![broken.url(), healthy.url()],
+            hash: None,
+            size: None,
+        };
+        let dir = tempfile::tempdir().unwrap();
+
+        let served_by = manager
+            .download(&artifact, &dir.path().join("first.bin"))
+            .await
+            .unwrap();
+        assert_eq!(served_by, DownloadSource::Mirror(healthy.url()));
+        assert_eq!(std::fs::read(dir.path().join("first.bin")).unwrap(), body);
+
+        // the broken host is now tried last
+        let served_by = manager
+            .download(&artifact, &dir.path().join("second.bin"))
+            .await
+            .unwrap();
+        assert_eq!(served_by, DownloadSource::Mirror(healthy.url()));
+        assert_eq!(broken.get_requests(), 1);
+        assert_eq!(healthy.get_requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_missing_file_keeps_host_healthy() {
+        let body = test_body(1000);
+        let first = TestServer::start(body.clone()).await;
+        first.push_fault(Fault::Status(404));
+        let second = TestServer::start(body.clone()).await;
+        let mut manager = DownloadManager::new(test_client(), &BTreeMap::new());
+        manager.retry = no_retry();
+        let artifact = Artifact {
+            name: "file.bin".to_string(),
+            mirrors: vec![first.url(), second.url()],
+            hash: None,
+            size: None,
+        };
+        let dir = tempfile::tempdir().unwrap();
+
+        let served_by = manager
+            .download(&artifact, &dir.path().join("first.bin"))
+            .await
+            .unwrap();
+        assert_eq!(served_by, DownloadSource::Mirror(second.url()));
+
+        // a 404 says nothing about the host, it is still tried first
+        let served_by = manager
+            .download(&artifact, &dir.path().join("second.bin"))
+            .await
+            .unwrap();
+        assert_eq!(served_by, DownloadSource::Mirror(first.url()));
+    }
+
+    #[tokio::test]
+    async fn test_download_uses_cache() {
+        let body = test_body(1000);
+        let server = TestServer::start(body.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = DownloadManager::new(test_client(), &BTreeMap::new());
+        manager.cache = Some(DownloadCache::new(dir.path().join("cache"), None, None));
+        let artifact = Artifact {
+            name: "file.bin".to_string(),
+            mirrors: vec![server.url()],
+            hash: Some(Hash::Sha256(hex::encode(
+                <sha2::Sha256 as sha2::Digest>::digest(&body),
+            ))),
+            size: None,
+        };
+
+        let first = dir.path().join("first.bin");
+        let served_by = manager.download(&artifact, &first).await.unwrap();
+        assert_eq!(served_by, DownloadSource::Mirror(server.url()));
+        std::fs::remove_file(&first).unwrap();
+
+        let second = dir.path().join("second.bin");
+        let served_by = manager.download(&artifact, &second).await.unwrap();
+        assert_eq!(served_by, DownloadSource::Cache);
+        assert_eq!(std::fs::read(&second).unwrap(), body);
+        assert_eq!(server.get_requests(), 1);
+    }
+}
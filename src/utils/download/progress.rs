@@ -0,0 +1,460 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+/// Report chunk progress at most once per this many bytes
+const REPORT_INTERVAL: u64 = 256 * 1024;
+
+/// Tells the events of concurrent downloads apart, even of the same URL
+pub type DownloadId = u64;
+
+/// A fresh id for a download that is about to start
+pub fn next_download_id() -> DownloadId {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Something that happened to a download, identified by its [DownloadId]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DownloadEvent {
+    /// The download to `dest` began, `total` is unknown until the server tells
+    Started {
+        id: DownloadId,
+        url: String,
+        dest: PathBuf,
+        total: Option<u64>,
+        chunks: usize,
+    },
+    /// Bytes of one chunk written so far, single-threaded downloads have one chunk
+    Progress {
+        id: DownloadId,
+        url: String,
+        chunk: usize,
+        done: u64,
+        total: Option<u64>,
+    },
+    /// An attempt failed and is about to be retried
+    Retry {
+        id: DownloadId,
+        url: String,
+        chunk: Option<usize>,
+        attempt: u32,
+        error: String,
+    },
+    /// The file matched its expected hash
+    Verified {
+        id: DownloadId,
+        url: String,
+        hash: String,
+    },
+    /// The file is complete and in place
+    Finished {
+        id: DownloadId,
+        url: String,
+        size: u64,
+    },
+    /// The download gave up
+    Failed {
+        id: DownloadId,
+        url: String,
+        error: String,
+    },
+}
+
+impl DownloadEvent {
+    pub fn id(&self) -> DownloadId {
+        match self {
+            DownloadEvent::Started { id, .. }
+            | DownloadEvent::Progress { id, .. }
+            | DownloadEvent::Retry { id, .. }
+            | DownloadEvent::Verified { id, .. }
+            | DownloadEvent::Finished { id, .. }
+            | DownloadEvent::Failed { id, .. } => *id,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        match self {
+            DownloadEvent::Started { url, .. }
+            | DownloadEvent::Progress { url, .. }
+            | DownloadEvent::Retry { url, .. }
+            | DownloadEvent::Verified { url, .. }
+            | DownloadEvent::Finished { url, .. }
+            | DownloadEvent::Failed { url, .. } => url,
+        }
+    }
+}
+
+/// Receives the events of every download
+pub trait ProgressSubscriber: Send + Sync {
+    fn on_event(&self, event: &DownloadEvent);
+}
+
+static SUBSCRIBERS: RwLock<Vec<Arc<dyn ProgressSubscriber>>> = RwLock::new(Vec::new());
+
+/// Register a subscriber for the events of all following downloads
+pub fn subscribe(subscriber: Arc<dyn ProgressSubscriber>) {
+    SUBSCRIBERS.write().unwrap().push(subscriber);
+}
+
+/// Send an event to every subscriber
+pub fn emit(event: DownloadEvent) {
+    for subscriber in SUBSCRIBERS.read().unwrap().iter() {
+        subscriber.on_event(&event);
+    }
+}
+
+/// Reports the progress of one chunk, throttled to [REPORT_INTERVAL]
+pub struct ChunkProgress<'a> {
+    id: DownloadId,
+    url: &'a str,
+    chunk: usize,
+    total: Option<u64>,
+    done: u64,
+    reported: u64,
+}
+
+impl<'a> ChunkProgress<'a> {
+    /// Start reporting a chunk with `done` bytes already present, e.g. after resuming
+    pub fn new(id: DownloadId, url: &'a str, chunk: usize, done: u64, total: Option<u64>) -> Self {
+        let progress = Self {
+            id,
+            url,
+            chunk,
+            total,
+            done,
+            reported: done,
+        };
+        progress.report();
+        progress
+    }
+
+    pub fn advance(&mut self, bytes: u64) {
+        self.done += bytes;
+        if self.done - self.reported >= REPORT_INTERVAL || Some(self.done) == self.total {
+            self.reported = self.done;
+            self.report();
+        }
+    }
+
+    fn report(&self) {
+        emit(DownloadEvent::Progress {
+            id: self.id,
+            url: self.url.to_string(),
+            chunk: self.chunk,
+            done: self.done,
+            total: self.total,
+        });
+    }
+}
+
+/// Aggregated progress of one download
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProgressSnapshot {
+    pub done: u64,
+    pub total: Option<u64>,
+    /// Bytes per second transferred since the download started
+    pub bytes_per_sec: f64,
+    /// Remaining time at the current rate
+    pub eta_secs: Option<u64>,
+}
+
+#[derive(Debug)]
+struct TrackedDownload {
+    started: Instant,
+    total: Option<u64>,
+    /// `(bytes present when first reported, bytes done)` per chunk
+    chunks: HashMap<usize, (u64, u64)>,
+}
+
+/// Folds events into per-download totals, rate and ETA
+#[derive(Debug, Default)]
+pub struct ProgressTracker {
+    downloads: HashMap<DownloadId, TrackedDownload>,
+}
+
+impl ProgressTracker {
+    /// Apply an event, returning the updated progress of its download
+    pub fn update(&mut self, event: &DownloadEvent) -> Option<ProgressSnapshot> {
+        self.update_at(event, Instant::now())
+    }
+
+    fn update_at(&mut self, event: &DownloadEvent, now: Instant) -> Option<ProgressSnapshot> {
+        match event {
+            DownloadEvent::Started { id, total, .. } => {
+                self.downloads.insert(
+                    *id,
+                    TrackedDownload {
+                        started: now,
+                        total: *total,
+                        chunks: HashMap::new(),
+                    },
+                );
+            }
+            DownloadEvent::Progress {
+                id, chunk, done, ..
+            } => {
+                let download = self.downloads.get_mut(id)?;
+                download
+                    .chunks
+                    .entry(*chunk)
+                    .and_modify(|(_, chunk_done)| *chunk_done = *done)
+                    .or_insert((*done, *done));
+                // single-threaded downloads learn the size with the first response
+                if download.chunks.len() == 1
+                    && let DownloadEvent::Progress {
+                        total: Some(total), ..
+                    } = event
+                {
+                    download.total.get_or_insert(*total);
+                }
+            }
+            DownloadEvent::Finished { id, .. } | DownloadEvent::Failed { id, .. } => {
+                self.downloads.remove(id);
+                return None;
+            }
+            _ => {}
+        }
+        let download = self.downloads.get(&event.id())?;
+
+        let done: u64 = download.chunks.values().map(|(_, done)| done).sum();
+        let transferred: u64 = download
+            .chunks
+            .values()
+            .map(|(initial, done)| done.saturating_sub(*initial))
+            .sum();
+        let elapsed = now.duration_since(download.started).as_secs_f64();
+        let bytes_per_sec = if elapsed > 0.0 {
+            transferred as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta_secs = download
+            .total
+            .filter(|_| bytes_per_sec > 0.0)
+            .map(|total| (total.saturating_sub(done) as f64 / bytes_per_sec).ceil() as u64);
+        Some(ProgressSnapshot {
+            done,
+            total: download.total,
+            bytes_per_sec,
+            eta_secs,
+        })
+    }
+}
+
+/// Renders one progress bar per running download
+pub struct ProgressBars {
+    bars: MultiProgress,
+    state: Mutex<(ProgressTracker, HashMap<DownloadId, ProgressBar>)>,
+}
+
+impl ProgressBars {
+    pub fn new() -> Self {
+        Self {
+            bars: MultiProgress::new(),
+            state: Mutex::new((ProgressTracker::default(), HashMap::new())),
+        }
+    }
+
+    fn create_bar(&self, dest: &Path, total: Option<u64>) -> ProgressBar {
+        let name = dest
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let bar = match total {
+            Some(total) => ProgressBar::new(total).with_style(
+                ProgressStyle::with_template(
+                    "{msg} [{bar:30}] {binary_bytes}/{binary_total_bytes} \
+                     {binary_bytes_per_sec} ETA {eta}",
+                )
+                .unwrap()
+                .progress_chars("=> "),
+            ),
+            None => ProgressBar::new_spinner().with_style(
+                ProgressStyle::with_template(
+                    "{msg} {spinner} {binary_bytes} {binary_bytes_per_sec}",
+                )
+                .unwrap(),
+            ),
+        };
+        self.bars.add(bar.with_message(name))
+    }
+}
+
+impl Default for ProgressBars {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressSubscriber for ProgressBars {
+    fn on_event(&self, event: &DownloadEvent) {
+        let mut state = self.state.lock().unwrap();
+        let (tracker, bars) = &mut *state;
+        let snapshot = tracker.update(event);
+        let id = event.id();
+        match event {
+            DownloadEvent::Started { dest, total, .. } => {
+                let bar = self.create_bar(dest, *total);
+                bars.insert(id, bar);
+            }
+            DownloadEvent::Finished { .. } => {
+                if let Some(bar) = bars.remove(&id) {
+                    bar.finish();
+                }
+            }
+            DownloadEvent::Failed { url, error, .. } => {
+                if let Some(bar) = bars.remove(&id) {
+                    bar.abandon_with_message(format!("{url}: {error}"));
+                }
+            }
+            _ => {
+                if let (Some(bar), Some(snapshot)) = (bars.get(&id), snapshot) {
+                    if let Some(total) = snapshot.total {
+                        bar.set_length(total);
+                    }
+                    bar.set_position(snapshot.done);
+                }
+            }
+        }
+    }
+}
+
+/// Writes every event as one JSON object per line, for frontends wrapping the bootstrap.
+///
+/// Events carry the aggregated `progress` of their download where known.
+pub struct JsonEventStream<W: Write + Send> {
+    state: Mutex<(ProgressTracker, W)>,
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    #[serde(flatten)]
+    event: &'a DownloadEvent,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    progress: Option<ProgressSnapshot>,
+}
+
+impl<W: Write + Send> JsonEventStream<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            state: Mutex::new((ProgressTracker::default(), writer)),
+        }
+    }
+}
+
+impl<W: Write + Send> ProgressSubscriber for JsonEventStream<W> {
+    fn on_event(&self, event: &DownloadEvent) {
+        let mut state = self.state.lock().unwrap();
+        let (tracker, writer) = &mut *state;
+        let line = JsonLine {
+            event,
+            progress: tracker.update(event),
+        };
+        if let Ok(json) = serde_json::to_string(&line) {
+            let _ = writeln!(writer, "{json}");
+            let _ = writer.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_progress_tracker() {
+        let url = "https://example.com/jdk.zip".to_string();
+        let start = Instant::now();
+        let mut tracker = ProgressTracker::default();
+        tracker.update_at(
+            &DownloadEvent::Started {
+                id: 1,
+                url: url.clone(),
+                dest: PathBuf::from("jdk.zip"),
+                total: Some(1000),
+                chunks: 2,
+            },
+            start,
+        );
+        // chunk 1 was resumed with 100 bytes present
+        for (chunk, done) in [(0, 0), (1, 100), (0, 200), (1, 300)] {
+            tracker.update_at(
+                &DownloadEvent::Progress {
+                    id: 1,
+                    url: url.clone(),
+                    chunk,
+                    done,
+                    total: Some(500),
+                },
+                start,
+            );
+        }
+        let snapshot = tracker
+            .update_at(
+                &DownloadEvent::Progress {
+                    id: 1,
+                    url: url.clone(),
+                    chunk: 0,
+                    done: 300,
+                    total: Some(500),
+                },
+                start + Duration::from_secs(2),
+            )
+            .unwrap();
+
+        assert_eq!(snapshot.done, 600);
+        assert_eq!(snapshot.total, Some(1000));
+        assert_eq!(snapshot.bytes_per_sec, 250.0);
+        assert_eq!(snapshot.eta_secs, Some(2));
+    }
+
+    #[test]
+    fn test_json_event_stream() {
+        let stream = JsonEventStream::new(Vec::new());
+        // the same URL downloaded to two places at once
+        for (id, dest) in [(1, "a"), (2, "b")] {
+            stream.on_event(&DownloadEvent::Started {
+                id,
+                url: "https://example.com/a".to_string(),
+                dest: PathBuf::from(dest),
+                total: None,
+                chunks: 1,
+            });
+        }
+        stream.on_event(&DownloadEvent::Finished {
+            id: 1,
+            url: "https://example.com/a".to_string(),
+            size: 5,
+        });
+        stream.on_event(&DownloadEvent::Progress {
+            id: 2,
+            url: "https://example.com/a".to_string(),
+            chunk: 0,
+            done: 3,
+            total: None,
+        });
+
+        let output = String::from_utf8(stream.state.into_inner().unwrap().1).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[0],
+            r#"{"event":"started","id":1,"url":"https://example.com/a","dest":"a","total":null,"chunks":1,"progress":{"done":0,"total":null,"bytes_per_sec":0.0,"eta_secs":null}}"#
+        );
+        assert_eq!(
+            lines[2],
+            r#"{"event":"finished","id":1,"url":"https://example.com/a","size":5}"#
+        );
+        // the second download is still tracked
+        assert!(lines[3].starts_with(r#"{"event":"progress","id":2,"#));
+        assert!(lines[3].contains(r#""progress":{"done":3,"#));
+    }
+}
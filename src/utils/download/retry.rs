@@ -0,0 +1,220 @@
+use crate::utils::download::DownloadError;
+use crate::utils::random::random_u64;
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use std::error::Error;
+use std::io;
+use std::time::{Duration, SystemTime};
+
+/// How often and how patiently failed requests are retried
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every further one
+    pub base_delay: Duration,
+    /// Upper bound of any delay, including ones asked for with `Retry-After`
+    pub max_delay: Duration,
+    /// Randomize delays, so parallel chunks do not retry in lockstep
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether to try again after `attempt` (starting at 1) failed with `error`
+    pub fn should_retry(&self, attempt: u32, error: &DownloadError) -> bool {
+        attempt < self.max_attempts && error.is_transient()
+    }
+
+    /// How long to wait before the attempt following `attempt`.
+    ///
+    /// A `Retry-After` sent by the server wins over the backoff.
+    pub fn delay(&self, attempt: u32, error: &DownloadError) -> Duration {
+        if let Some(retry_after) = error.retry_after() {
+            return retry_after.min(self.max_delay);
+        }
+        let exponent = attempt.saturating_sub(1).min(16);
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        if self.jitter {
+            // "equal jitter": keep half of the delay, randomize the other half
+            let half = delay / 2;
+            let random = (random_u64() % (half.as_millis() as u64 + 1)) as u32;
+            half + Duration::from_millis(random.into())
+        } else {
+            delay
+        }
+    }
+}
+
+/// Parse a `Retry-After` header, either delay seconds or an HTTP date
+pub fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+impl DownloadError {
+    /// Whether trying again may help: timeouts, dropped connections,
+    /// server errors and rate limiting. Hash mismatches, missing files or
+    /// local errors are permanent.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            DownloadError::Http(err) => match err.status() {
+                Some(status) => is_transient_status(status),
+                None => {
+                    err.is_timeout()
+                        || err.is_connect()
+                        || err.is_request()
+                        || err.is_body()
+                        // a body cut off midway surfaces as a decode error
+                        || (err.is_decode() && has_transient_io_source(err))
+                }
+            },
+            DownloadError::Status { status, .. } => is_transient_status(*status),
+            DownloadError::Io(err) => is_transient_io(err),
+            // the connection dropped before the announced length arrived
+            DownloadError::LengthMismatch { .. } => true,
+            DownloadError::ChunkFailed { source, .. }
+            | DownloadError::MaxRetriesExceeded { source, .. }
+            | DownloadError::MirrorsExhausted { source, .. } => source.is_transient(),
+            DownloadError::Hashing(_)
+            | DownloadError::Unarchive(_)
+            | DownloadError::FailedCreateParentFolders(_)
+            | DownloadError::NoMirrors(_)
+            | DownloadError::SizeMismatch { .. }
+            | DownloadError::RangeNotHonored { .. }
+            | DownloadError::ContentRangeMismatch { .. } => false,
+        }
+    }
+
+    /// The delay the server asked for
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            DownloadError::Status { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+fn is_transient_io(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::Interrupted
+    )
+}
+
+fn has_transient_io_source(err: &(dyn Error + 'static)) -> bool {
+    let mut source = err.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>()
+            && is_transient_io(err)
+        {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn status_error(status: u16, retry_after: Option<Duration>) -> DownloadError {
+        DownloadError::Status {
+            url: "https://example.com/file".to_string(),
+            status: StatusCode::from_u16(status).unwrap(),
+            retry_after,
+        }
+    }
+
+    #[test]
+    fn test_error_classification() {
+        assert!(status_error(503, None).is_transient());
+        assert!(status_error(429, None).is_transient());
+        assert!(!status_error(404, None).is_transient());
+        assert!(!status_error(403, None).is_transient());
+        assert!(DownloadError::Io(io::Error::from(io::ErrorKind::ConnectionReset)).is_transient());
+        assert!(!DownloadError::Io(io::Error::from(io::ErrorKind::StorageFull)).is_transient());
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            jitter: false,
+        };
+        let error = status_error(503, None);
+        assert_eq!(policy.delay(1, &error), Duration::from_millis(100));
+        assert_eq!(policy.delay(3, &error), Duration::from_millis(400));
+        assert_eq!(policy.delay(8, &error), Duration::from_secs(1));
+        // the server knows best, within limits
+        assert_eq!(
+            policy.delay(1, &status_error(503, Some(Duration::from_millis(700)))),
+            Duration::from_millis(700)
+        );
+        assert_eq!(
+            policy.delay(1, &status_error(503, Some(Duration::from_secs(60)))),
+            Duration::from_secs(1)
+        );
+
+        let jittered = RetryPolicy {
+            jitter: true,
+            ..policy
+        };
+        for _ in 0..20 {
+            let delay = jittered.delay(3, &error);
+            assert!((Duration::from_millis(200)..=Duration::from_millis(400)).contains(&delay));
+        }
+
+        assert!(!jittered.should_retry(10, &error));
+        assert!(!jittered.should_retry(1, &status_error(404, None)));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(120)));
+
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(parse_retry_after(&headers), Some(Duration::ZERO));
+    }
+}
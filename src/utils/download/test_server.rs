@@ -0,0 +1,232 @@
+//! A tiny HTTP/1.1 server standing in for download mirrors in tests.
+//!
+//! It serves one body at every path unless a route overrides it, supports `HEAD`,
+//! single `Range` requests and `If-None-Match`, and can inject faults into requests.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A fault applied to the next `GET` request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Respond with this status and an empty body
+    Status(u16),
+    /// Announce the full length, but close the connection after this many body bytes
+    Truncate(usize),
+    /// Respond with the whole body, as if ranges were not supported
+    IgnoreRange,
+    /// Answer a range request with a range starting this many bytes earlier
+    ShiftRange(usize),
+}
+
+pub struct ServerState {
+    pub body: Vec<u8>,
+    pub accept_ranges: bool,
+    pub etag: Option<String>,
+    /// Bodies served at specific paths instead of `body`
+    pub routes: Mutex<HashMap<String, Vec<u8>>>,
+    /// Faults consumed by the following `GET` requests, in order
+    pub faults: Mutex<VecDeque<Fault>>,
+    /// Faults consumed by the following `HEAD` requests, only statuses apply
+    pub head_faults: Mutex<VecDeque<Fault>>,
+    /// `GET` requests for ranges starting at these offsets always fail with 500
+    pub broken_offsets: Mutex<HashSet<u64>>,
+    /// The `Range` header of every `GET` request
+    pub ranges: Mutex<Vec<Option<String>>>,
+    pub get_requests: AtomicUsize,
+}
+
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub state: Arc<ServerState>,
+}
+
+impl TestServer {
+    /// Serve `body` with range support and a strong ETag
+    pub async fn start(body: Vec<u8>) -> Self {
+        Self::start_with(body, true).await
+    }
+
+    pub async fn start_with(body: Vec<u8>, accept_ranges: bool) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(ServerState {
+            body,
+            accept_ranges,
+            etag: Some("\"test-etag\"".to_string()),
+            routes: Mutex::new(HashMap::new()),
+            faults: Mutex::new(VecDeque::new()),
+            head_faults: Mutex::new(VecDeque::new()),
+            broken_offsets: Mutex::new(HashSet::new()),
+            ranges: Mutex::new(Vec::new()),
+            get_requests: AtomicUsize::new(0),
+        });
+
+        let server_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle(stream, Arc::clone(&server_state)));
+            }
+        });
+        Self { addr, state }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/file.bin", self.addr)
+    }
+
+    /// Serve `body` at `path` instead of the default body
+    pub fn route(&self, path: &str, body: impl Into<Vec<u8>>) {
+        self.state
+            .routes
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), body.into());
+    }
+
+    pub fn push_fault(&self, fault: Fault) {
+        self.state.faults.lock().unwrap().push_back(fault);
+    }
+
+    pub fn push_head_fault(&self, fault: Fault) {
+        self.state.head_faults.lock().unwrap().push_back(fault);
+    }
+
+    pub fn get_requests(&self) -> usize {
+        self.state.get_requests.load(Ordering::SeqCst)
+    }
+}
+
+/// A client which never goes through a proxy from the environment
+pub fn test_client() -> reqwest::Client {
+    reqwest::Client::builder().no_proxy().build().unwrap()
+}
+
+/// Deterministic, non-repeating test content
+pub fn test_body(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+async fn handle(mut stream: TcpStream, state: Arc<ServerState>) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut lines = request.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default();
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let routed = state.routes.lock().unwrap().get(path).cloned();
+    let full_body = routed.as_deref().unwrap_or(&state.body);
+    let total = full_body.len();
+    let mut head = String::from("Content-Type: application/octet-stream\r\nConnection: close\r\n");
+    if state.accept_ranges {
+        head.push_str("Accept-Ranges: bytes\r\n");
+    }
+    if let Some(etag) = &state.etag {
+        head.push_str(&format!("ETag: {etag}\r\n"));
+    }
+
+    if method == "HEAD" {
+        let fault = state.head_faults.lock().unwrap().pop_front();
+        if let Some(Fault::Status(status)) = fault {
+            return respond_status(&mut stream, status).await;
+        }
+        let response = format!("HTTP/1.1 200 OK\r\n{head}Content-Length: {total}\r\n\r\n");
+        return stream.write_all(response.as_bytes()).await;
+    }
+
+    state.get_requests.fetch_add(1, Ordering::SeqCst);
+    let range_header = headers.get("range").cloned();
+    state.ranges.lock().unwrap().push(range_header.clone());
+    let fault = state.faults.lock().unwrap().pop_front();
+
+    let range = range_header
+        .as_deref()
+        .and_then(|range| parse_range(range, total));
+    if let Some((start, _)) = range
+        && state
+            .broken_offsets
+            .lock()
+            .unwrap()
+            .contains(&(start as u64))
+    {
+        return respond_status(&mut stream, 500).await;
+    }
+    if let Some(Fault::Status(status)) = fault {
+        return respond_status(&mut stream, status).await;
+    }
+
+    if let (Some(validator), Some(etag)) = (headers.get("if-none-match"), &state.etag)
+        && validator == etag
+    {
+        let response = format!("HTTP/1.1 304 Not Modified\r\n{head}\r\n");
+        stream.write_all(response.as_bytes()).await?;
+        return stream.shutdown().await;
+    }
+
+    let if_range_matches = match (headers.get("if-range"), &state.etag) {
+        (Some(validator), Some(etag)) => validator == etag,
+        (Some(_), None) => false,
+        (None, _) => true,
+    };
+    let honor_range = state.accept_ranges && if_range_matches && fault != Some(Fault::IgnoreRange);
+
+    let (status_line, body) = match (range_header.is_some() && honor_range, range) {
+        (true, Some((start, end))) => {
+            let start = match fault {
+                Some(Fault::ShiftRange(shift)) => start.saturating_sub(shift),
+                _ => start,
+            };
+            head.push_str(&format!("Content-Range: bytes {start}-{end}/{total}\r\n"));
+            ("206 Partial Content", &full_body[start..=end])
+        }
+        (true, None) => return respond_status(&mut stream, 416).await,
+        (false, _) => ("200 OK", full_body),
+    };
+    let response = format!(
+        "HTTP/1.1 {status_line}\r\n{head}Content-Length: {}\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    let body = match fault {
+        Some(Fault::Truncate(length)) => &body[..length.min(body.len())],
+        _ => body,
+    };
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
+
+/// Parse `bytes=<start>-[<end>]` into an inclusive range within `total`
+fn parse_range(range: &str, total: usize) -> Option<(usize, usize)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let start: usize = start.parse().ok()?;
+    let end = match end {
+        "" => total.checked_sub(1)?,
+        end => end.parse::<usize>().ok()?.min(total.checked_sub(1)?),
+    };
+    (start <= end).then_some((start, end))
+}
+
+async fn respond_status(stream: &mut TcpStream, status: u16) -> std::io::Result<()> {
+    let response =
+        format!("HTTP/1.1 {status} Test Fault\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -1,4 +1,5 @@
 use git2::{Error, Repository};
+use std::path::Path;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum FastForwardStatus {
@@ -26,4 +27,25 @@ pub fn fast_forward(repo: &Repository, branch: &str) -> Result<FastForwardStatus
     } else {
         Err(Error::from_str("Fast-forward only!"))
     }
-}
\ No newline at end of file
+}
+
+/// Clone `source` into `dest` and check out `revision` with a detached HEAD.
+///
+/// `source` may be a local repository, so fresh working copies can be made without network.
+pub fn clone_at_revision(source: &str, dest: &Path, revision: &str) -> Result<Repository, Error> {
+    let repo = Repository::clone(source, dest)?;
+    {
+        let commit = repo.revparse_single(revision)?.peel_to_commit()?;
+        repo.checkout_tree(
+            commit.as_object(),
+            Some(git2::build::CheckoutBuilder::default().force()),
+        )?;
+        repo.set_head_detached(commit.id())?;
+    }
+    Ok(repo)
+}
+
+/// The id of the commit HEAD points to
+pub fn head_commit(repo: &Repository) -> Result<String, Error> {
+    Ok(repo.head()?.peel_to_commit()?.id().to_string())
+}
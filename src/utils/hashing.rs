@@ -1,21 +1,25 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use bytes::Bytes;
 use digest::{Digest, OutputSizeUser, generic_array::ArrayLength};
 use serde::{Deserialize, Serialize};
 use std::{
+    fmt,
     ops::Add,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 use thiserror::Error;
 use tokio::io::{AsyncReadExt, BufReader};
 
 #[derive(Error, Debug)]
 pub enum HashingError {
-    #[error("Hash mismatch: expected {expected_hash:?}, got {actual_hash}")]
+    #[error("Hash mismatch: expected {expected_hash}, got {actual_hash}")]
     HashNotMatch {
         expected_hash: Hash,
         actual_hash: String,
     },
-    #[error("Hash mismatch for file {file_path}: expected {expected_hash:?}, got {actual_hash}")]
+    #[error("Hash mismatch for file {file_path}: expected {expected_hash}, got {actual_hash}")]
     FileHashNotMatch {
         file_path: Box<PathBuf>,
         expected_hash: Hash,
@@ -32,7 +36,33 @@ pub enum HashingError {
     UnsupportedHashFunction(String),
 }
 
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseHashError {
+    #[error("Unknown hash algorithm {0}")]
+    UnknownAlgorithm(String),
+
+    #[error("Cannot infer the hash algorithm of a {0} digit digest")]
+    UnknownLength(usize),
+
+    #[error("{algorithm} digests have {expected} hex digits, got {actual}")]
+    BadLength {
+        algorithm: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+
+    #[error("Digest {0:?} is not hex, or mixes upper and lower case")]
+    BadHex(String),
+
+    #[error("Digest {0:?} is not base64")]
+    BadBase64(String),
+}
+
+/// An expected digest, written as `sha256:<hex>`, SRI-style `sha256-<base64>` or bare hex.
+///
+/// Bare hex infers the algorithm from its length. Hex digits are stored in lowercase.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub enum Hash {
     Md5(String),
     Sha1(String),
@@ -40,7 +70,38 @@ pub enum Hash {
     Sha512(String),
 }
 
+/// `(name, hex digits)` of every algorithm, names as used in `<name>:<hex>`
+const ALGORITHMS: [(&str, usize); 4] = [("md5", 32), ("sha1", 40), ("sha256", 64), ("sha512", 128)];
+
 impl Hash {
+    /// Build a hash from an algorithm name like `sha256` and a digest in lowercase hex
+    fn from_parts(algorithm: &str, digest: String) -> Result<Self, ParseHashError> {
+        Ok(match algorithm.to_lowercase().as_str() {
+            "md5" => Hash::Md5(digest),
+            "sha1" => Hash::Sha1(digest),
+            "sha256" => Hash::Sha256(digest),
+            "sha512" => Hash::Sha512(digest),
+            _ => return Err(ParseHashError::UnknownAlgorithm(algorithm.to_string())),
+        })
+    }
+
+    /// A hex digest of a known algorithm like `sha256`, e.g. the content of a `.sha256` file
+    pub fn from_hex(algorithm: &str, digest: &str) -> Result<Self, ParseHashError> {
+        let expected = expected_hex_length(algorithm)?;
+        Hash::from_parts(algorithm, parse_hex(digest.trim(), algorithm, expected)?)
+    }
+
+    /// The SRI form, e.g. `sha256-<base64>`, fails for hashes built from invalid hex
+    pub fn to_sri(&self) -> Result<String, ParseHashError> {
+        let digest =
+            hex::decode(self.value()).map_err(|_| ParseHashError::BadHex(self.value().into()))?;
+        Ok(format!(
+            "{}-{}",
+            self.hash_type().to_lowercase(),
+            BASE64.encode(digest)
+        ))
+    }
+
     pub fn hash_type(&self) -> &'static str {
         match self {
             Hash::Md5(_) => "MD5",
@@ -66,6 +127,91 @@ impl Hash {
     }
 }
 
+impl FromStr for Hash {
+    type Err = ParseHashError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if let Some((algorithm, digest)) = value.split_once(':') {
+            return Hash::from_hex(algorithm, digest);
+        }
+        if let Some((algorithm, digest)) = value.split_once('-')
+            && let Ok(expected) = expected_hex_length(algorithm)
+        {
+            let bytes = BASE64
+                .decode(digest)
+                .map_err(|_| ParseHashError::BadBase64(digest.to_string()))?;
+            if bytes.len() * 2 != expected {
+                return Err(ParseHashError::BadLength {
+                    algorithm: canonical_name(algorithm),
+                    expected,
+                    actual: bytes.len() * 2,
+                });
+            }
+            return Hash::from_parts(algorithm, hex::encode(bytes));
+        }
+        let (algorithm, expected) = ALGORITHMS
+            .into_iter()
+            .find(|(_, length)| *length == value.len())
+            .ok_or(ParseHashError::UnknownLength(value.len()))?;
+        Hash::from_parts(algorithm, parse_hex(value, algorithm, expected)?)
+    }
+}
+
+impl fmt::Display for Hash {
+    /// Formats as `<algorithm>:<hex>`, e.g. `sha256:b94d27...`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.hash_type().to_lowercase(), self.value())
+    }
+}
+
+impl TryFrom<String> for Hash {
+    type Error = ParseHashError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Hash> for String {
+    fn from(hash: Hash) -> Self {
+        hash.to_string()
+    }
+}
+
+fn canonical_name(algorithm: &str) -> &'static str {
+    ALGORITHMS
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(algorithm))
+        .map_or("unknown", |(name, _)| name)
+}
+
+fn expected_hex_length(algorithm: &str) -> Result<usize, ParseHashError> {
+    ALGORITHMS
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(algorithm))
+        .map(|(_, length)| length)
+        .ok_or_else(|| ParseHashError::UnknownAlgorithm(algorithm.to_string()))
+}
+
+/// Validate a hex digest of one case, returning it in lowercase
+fn parse_hex(digest: &str, algorithm: &str, expected: usize) -> Result<String, ParseHashError> {
+    let all_hex = digest.chars().all(|c| c.is_ascii_hexdigit());
+    let mixed_case = digest.chars().any(|c| c.is_ascii_lowercase())
+        && digest.chars().any(|c| c.is_ascii_uppercase());
+    if !all_hex || mixed_case {
+        return Err(ParseHashError::BadHex(digest.to_string()));
+    }
+    if digest.len() != expected {
+        return Err(ParseHashError::BadLength {
+            algorithm: canonical_name(algorithm),
+            expected,
+            actual: digest.len(),
+        });
+    }
+    Ok(digest.to_lowercase())
+}
+
 /// Asynchronously streams a file and calculates its hash using a generic hasher.
 ///
 /// This function reads the file in chunks to keep memory usage low, making it
@@ -224,6 +370,83 @@ mod tests {
         assert!(result.is_err());
     }
 
+    const HELLO_SHA256: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    #[test]
+    fn test_parse_hash() {
+        let expected = Hash::Sha256(HELLO_SHA256.to_string());
+        assert_eq!(
+            format!("sha256:{HELLO_SHA256}").parse(),
+            Ok(expected.clone())
+        );
+        assert_eq!(
+            format!("SHA256:{}", HELLO_SHA256.to_uppercase()).parse(),
+            Ok(expected.clone())
+        );
+        assert_eq!(HELLO_SHA256.parse(), Ok(expected.clone()));
+        assert_eq!(
+            "sha256-uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=".parse(),
+            Ok(expected.clone())
+        );
+        assert_eq!(
+            "5eb63bbbe01eeed093cb22bb8f5acdc3".parse(),
+            Ok(Hash::Md5("5eb63bbbe01eeed093cb22bb8f5acdc3".to_string()))
+        );
+
+        assert_eq!(expected.to_string(), format!("sha256:{HELLO_SHA256}"));
+        assert_eq!(
+            expected.to_sri(),
+            Ok("sha256-uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=".to_string())
+        );
+        assert!(Hash::Sha256("not hex".to_string()).to_sri().is_err());
+        assert_eq!(
+            Hash::from_hex("sha256", &format!("{}\n", HELLO_SHA256.to_uppercase())),
+            Ok(expected.clone())
+        );
+        assert!(Hash::from_hex("sha256", "5eb63bbbe01eeed093cb22bb8f5acdc3").is_err());
+    }
+
+    #[test]
+    fn test_parse_hash_errors() {
+        assert!(matches!(
+            format!("sha512:{HELLO_SHA256}").parse::<Hash>(),
+            Err(ParseHashError::BadLength {
+                algorithm: "sha512",
+                expected: 128,
+                actual: 64
+            })
+        ));
+        assert!(matches!(
+            "sha256:abc".parse::<Hash>(),
+            Err(ParseHashError::BadLength { actual: 3, .. })
+        ));
+        assert!(matches!(
+            "b94D27".parse::<Hash>(),
+            Err(ParseHashError::UnknownLength(6))
+        ));
+        let mixed = format!(
+            "{}{}",
+            &HELLO_SHA256[..32],
+            HELLO_SHA256[32..].to_uppercase()
+        );
+        assert!(matches!(
+            mixed.parse::<Hash>(),
+            Err(ParseHashError::BadHex(_))
+        ));
+        assert!(matches!(
+            format!("sha256:{}", &HELLO_SHA256[1..]).parse::<Hash>(),
+            Err(ParseHashError::BadLength { actual: 63, .. })
+        ));
+        assert!(matches!(
+            "crc32:abcd".parse::<Hash>(),
+            Err(ParseHashError::UnknownAlgorithm(_))
+        ));
+        assert!(matches!(
+            "sha256-not*base64".parse::<Hash>(),
+            Err(ParseHashError::BadBase64(_))
+        ));
+    }
+
     #[tokio::test]
     async fn test_calculate_file_hash() {
         // Create a temporary file
@@ -0,0 +1,117 @@
+use crate::config::HttpSettings;
+use reqwest::{Certificate, Client, NoProxy, Proxy};
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Sent with every request
+pub const USER_AGENT: &str = concat!("celestial-bootstrap-next/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Error, Debug)]
+pub enum HttpClientError {
+    #[error("Failed to read certificate {path}")]
+    ReadCertificate {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("Bad certificate {path}")]
+    BadCertificate {
+        path: PathBuf,
+        #[source]
+        source: reqwest::Error,
+    },
+
+    #[error("Bad proxy URL {0}")]
+    BadProxy(String, #[source] reqwest::Error),
+
+    #[error("no_proxy is set without proxy, set NO_PROXY for proxies from the environment")]
+    NoProxyWithoutProxy,
+
+    #[error("Failed to build the HTTP client")]
+    Build(#[from] reqwest::Error),
+}
+
+/// Build the client used for every request, configured by the `[http]` section.
+///
+/// Without a configured proxy, `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY` from the
+/// environment apply, and `no_proxy` must be empty.
+pub fn build_client(settings: &HttpSettings) -> Result<Client, HttpClientError> {
+    let mut builder = Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(settings.connect_timeout())
+        .read_timeout(settings.read_timeout());
+
+    if let Some(proxy_url) = &settings.proxy {
+        let no_proxy = if settings.no_proxy.is_empty() {
+            NoProxy::from_env()
+        } else {
+            NoProxy::from_string(&settings.no_proxy.join(","))
+        };
+        let proxy = Proxy::all(proxy_url)
+            .map_err(|err| HttpClientError::BadProxy(proxy_url.clone(), err))?
+            .no_proxy(no_proxy);
+        builder = builder.proxy(proxy);
+    } else if !settings.no_proxy.is_empty() {
+        return Err(HttpClientError::NoProxyWithoutProxy);
+    }
+
+    for path in &settings.ca_certificates {
+        let pem = std::fs::read(path).map_err(|source| HttpClientError::ReadCertificate {
+            path: path.clone(),
+            source,
+        })?;
+        let certificates = Certificate::from_pem_bundle(&pem).map_err(|source| {
+            HttpClientError::BadCertificate {
+                path: path.clone(),
+                source,
+            }
+        })?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    // HTTP/2 is negotiated over TLS where the server supports it
+    builder = if settings.http2 {
+        builder.http2_adaptive_window(true)
+    } else {
+        builder.http1_only()
+    };
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_client() {
+        let mut settings = HttpSettings {
+            proxy: Some("http://proxy.example:3128".to_string()),
+            no_proxy: vec!["localhost".to_string(), ".internal".to_string()],
+            ..HttpSettings::default()
+        };
+        assert!(build_client(&settings).is_ok());
+
+        settings.ca_certificates = vec![PathBuf::from("/nonexistent/ca.pem")];
+        assert!(matches!(
+            build_client(&settings),
+            Err(HttpClientError::ReadCertificate { .. })
+        ));
+
+        settings.ca_certificates.clear();
+        settings.proxy = Some("not a url".to_string());
+        assert!(matches!(
+            build_client(&settings),
+            Err(HttpClientError::BadProxy(..))
+        ));
+
+        settings.proxy = None;
+        assert!(matches!(
+            build_client(&settings),
+            Err(HttpClientError::NoProxyWithoutProxy)
+        ));
+    }
+}
@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+/// One lock per path, created on first use
+static LOCKS: LazyLock<Mutex<HashMap<PathBuf, Arc<AsyncMutex<()>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Wait until no other task of this process works on `path`, held until the guard is dropped.
+///
+/// Components are built concurrently and may provision the same distribution,
+/// this keeps them from sharing its part files and unpack directories.
+pub async fn lock_path(path: &Path) -> OwnedMutexGuard<()> {
+    let lock = {
+        let mut locks = LOCKS.lock().unwrap();
+        // forget locks nobody holds or waits for
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        Arc::clone(locks.entry(path.to_owned()).or_default())
+    };
+    lock.lock_owned().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_lock_path() {
+        let guard = lock_path(Path::new("/a")).await;
+        // other paths are not blocked
+        drop(lock_path(Path::new("/b")).await);
+
+        let waiter = tokio::spawn(lock_path(Path::new("/a")));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        drop(guard);
+        tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::process::{Child, Command};
+
+/// How long a process tree gets to exit after a graceful termination request
+const TERMINATE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Set once stdout carries a machine readable stream, e.g. `--progress json`
+static STDOUT_RESERVED: AtomicBool = AtomicBool::new(false);
+
+/// Keep the output of child processes out of our stdout from now on
+pub fn reserve_stdout() {
+    STDOUT_RESERVED.store(true, Ordering::Relaxed);
+}
+
+/// Send the child's stdout to our stderr if stdout is [reserved](reserve_stdout)
+pub fn protect_stdout(command: &mut Command) {
+    if STDOUT_RESERVED.load(Ordering::Relaxed) {
+        command.stdout(io::stderr());
+    }
+}
+
+/// Start the command as the leader of a new process group,
+/// so [kill_process_tree] can reach every process it spawns.
+pub fn spawn_process_group(command: &mut Command) -> io::Result<Child> {
+    #[cfg(unix)]
+    command.process_group(0);
+    protect_stdout(command);
+    command.spawn()
+}
+
+/// Terminate a child spawned with [spawn_process_group] together with its descendants,
+/// e.g. the Gradle daemon forked by the wrapper.
+///
+/// Descendants which left the process group with `setsid` are found through their
+/// parent process on Linux, as long as their parent is still alive.
+pub async fn kill_process_tree(child: &mut Child) -> io::Result<()> {
+    let Some(pid) = child.id() else {
+        // already reaped
+        return Ok(());
+    };
+
+    #[cfg(unix)]
+    {
+        // collect them before the leader exits and its children get reparented
+        let descendants = descendants(pid);
+        let signal_all = |signal| {
+            let pgid = pid as libc::pid_t;
+            unsafe { libc::killpg(pgid, signal) };
+            for &descendant in &descendants {
+                unsafe { libc::kill(descendant as libc::pid_t, signal) };
+            }
+        };
+
+        // ask politely first, then force the whole tree down
+        signal_all(libc::SIGTERM);
+        let _ = tokio::time::timeout(TERMINATE_GRACE_PERIOD, child.wait()).await;
+        // children of the leader may outlive it, make sure none are left
+        signal_all(libc::SIGKILL);
+    }
+
+    #[cfg(windows)]
+    {
+        let _ = TERMINATE_GRACE_PERIOD;
+        Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &pid.to_string()])
+            .output()
+            .await?;
+    }
+
+    // reap the leader, this is a no-op if it has already exited
+    child.kill().await
+}
+
+/// Every process descending from `pid`, read from `/proc`
+#[cfg(target_os = "linux")]
+fn descendants(pid: u32) -> Vec<u32> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    // (pid, parent pid) of every process
+    let processes: Vec<(u32, u32)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let pid = entry.file_name().to_str()?.parse().ok()?;
+            let stat = std::fs::read_to_string(entry.path().join("stat")).ok()?;
+            // `pid (comm) state ppid ...`, where comm may contain spaces and parentheses
+            let (_, fields) = stat.rsplit_once(')')?;
+            let parent = fields.split_whitespace().nth(1)?.parse().ok()?;
+            Some((pid, parent))
+        })
+        .collect();
+
+    let mut found = Vec::new();
+    let mut pending = vec![pid];
+    while let Some(parent) = pending.pop() {
+        for &(pid, _) in processes.iter().filter(|(_, ppid)| *ppid == parent) {
+            found.push(pid);
+            pending.push(pid);
+        }
+    }
+    found
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn descendants(_pid: u32) -> Vec<u32> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_kill_process_tree_reaches_detached_children() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(format!(
+            "setsid sleep 30 & echo $! > {}; wait",
+            pid_file.display()
+        ));
+        let mut child = spawn_process_group(&mut command).unwrap();
+
+        let detached: libc::pid_t = loop {
+            match std::fs::read_to_string(&pid_file) {
+                Ok(pid) if pid.ends_with('\n') => break pid.trim().parse().unwrap(),
+                _ => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        // wait until it has left the process group
+        while unsafe { libc::getpgid(detached) } != detached {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        kill_process_tree(&mut child).await.unwrap();
+
+        // the detached process is reparented, and killed, but not reaped by us
+        let gone = async {
+            while std::fs::read_to_string(format!("/proc/{detached}/stat")).is_ok_and(|stat| {
+                !stat
+                    .rsplit_once(')')
+                    .unwrap()
+                    .1
+                    .trim_start()
+                    .starts_with('Z')
+            }) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), gone)
+            .await
+            .expect("the detached process survived");
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+/// Parse a Java `.properties` document.
+///
+/// Supports `#`/`!` comments, `=`, `:` and whitespace separators, line continuations
+/// and the usual backslash escapes (including `\uXXXX`), which is enough for
+/// files like `gradle-wrapper.properties` where URLs are written as `https\://...`.
+pub fn parse_properties(content: &str) -> HashMap<String, String> {
+    let mut properties = HashMap::new();
+    let mut lines = content.lines();
+
+    while let Some(line) = lines.next() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+            continue;
+        }
+
+        // join continuation lines, a line ending with an odd number of backslashes continues
+        let mut logical_line = line.to_string();
+        while ends_with_continuation(&logical_line) {
+            logical_line.pop();
+            match lines.next() {
+                Some(next) => logical_line.push_str(next.trim_start()),
+                None => break,
+            }
+        }
+
+        let (key, value) = split_key_value(&logical_line);
+        properties.insert(unescape(key), unescape(value));
+    }
+
+    properties
+}
+
+fn ends_with_continuation(line: &str) -> bool {
+    line.chars().rev().take_while(|c| *c == '\\').count() % 2 == 1
+}
+
+fn split_key_value(line: &str) -> (&str, &str) {
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' => escaped = true,
+            '=' | ':' => return (&line[..index], line[index + 1..].trim_start()),
+            c if c.is_whitespace() => {
+                let rest = line[index..].trim_start();
+                let rest = rest
+                    .strip_prefix(['=', ':'])
+                    .map(str::trim_start)
+                    .unwrap_or(rest);
+                return (&line[..index], rest);
+            }
+            _ => (),
+        }
+    }
+    (line, "")
+}
+
+fn unescape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('f') => result.push('\u{c}'),
+            Some('u') => {
+                let code: String = chars.by_ref().take(4).collect();
+                if let Some(c) = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+                    result.push(c);
+                }
+            }
+            Some(other) => result.push(other),
+            None => (),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gradle_wrapper_properties() {
+        let properties = parse_properties(
+            "#Gradle wrapper\n\
+             distributionBase=GRADLE_USER_HOME\n\
+             distributionUrl=https\\://services.gradle.org/distributions/gradle-8.5-bin.zip\n\
+             ! another comment\n\
+             zipStorePath : wrapper/dists\n\
+             spaced value\n\
+             multi=first \\\n    second\n\
+             unicode=\\u0041\n",
+        );
+
+        assert_eq!(properties["distributionBase"], "GRADLE_USER_HOME");
+        assert_eq!(
+            properties["distributionUrl"],
+            "https://services.gradle.org/distributions/gradle-8.5-bin.zip"
+        );
+        assert_eq!(properties["zipStorePath"], "wrapper/dists");
+        assert_eq!(properties["spaced"], "value");
+        assert_eq!(properties["multi"], "first second");
+        assert_eq!(properties["unicode"], "A");
+        assert_eq!(properties.len(), 6);
+    }
+}
@@ -1,19 +1,142 @@
-use std::{env, io, path::PathBuf};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::{env, io};
 
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
 
-use crate::utils::timestamp::current_unix_timestamp_in_ms;
+use crate::utils::random::random_u64;
 
-/// An async implementation of [tempfile::tempfile]
-/// Please note that: you need to handle the delete action after you use the file
-pub async fn tempfile() -> io::Result<(fs::File, PathBuf)> {
-    let timestamp_in_ms = current_unix_timestamp_in_ms();
+/// Give up after this many name collisions in a row
+const MAX_ATTEMPTS: u32 = 16;
 
-    let path = env::temp_dir().join(format!("celestial-rs-{timestamp_in_ms}.tmp"));
-    let file = fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(&path)
-        .await?;
-    Ok((file, path))
+/// An async temporary file, deleted when dropped unless [TempFile::persist]ed.
+///
+/// Names get a random suffix and are created with `create_new`, so concurrent tasks
+/// never share a file. Dereferences to the underlying [fs::File], opened for reading
+/// and writing.
+#[derive(Debug)]
+pub struct TempFile {
+    file: Option<fs::File>,
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Create a temp file in the system temp directory
+    pub async fn new() -> io::Result<Self> {
+        Self::new_in(&env::temp_dir()).await
+    }
+
+    /// Create a temp file in `dir`.
+    ///
+    /// Use a directory on the same filesystem as the final destination,
+    /// so [TempFile::persist] is an atomic rename.
+    pub async fn new_in(dir: &Path) -> io::Result<Self> {
+        for _ in 0..MAX_ATTEMPTS {
+            let path = dir.join(format!("celestial-rs-{:016x}.tmp", random_u64()));
+            match fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .await
+            {
+                Ok(file) => {
+                    return Ok(Self {
+                        file: Some(file),
+                        path,
+                    });
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("No unique temp file name found in {}", dir.display()),
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Flush and close the file, then move it to `dest`, replacing any existing file.
+    ///
+    /// On failure the temp file is still deleted.
+    pub async fn persist(mut self, dest: &Path) -> io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush().await?;
+        }
+        fs::rename(&self.path, dest).await?;
+        // nothing left to delete
+        self.path = PathBuf::new();
+        Ok(())
+    }
+}
+
+impl Deref for TempFile {
+    type Target = fs::File;
+
+    fn deref(&self) -> &Self::Target {
+        self.file
+            .as_ref()
+            .expect("temp file is open until persisted")
+    }
+}
+
+impl DerefMut for TempFile {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.file
+            .as_mut()
+            .expect("temp file is open until persisted")
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // close the handle first, Windows cannot delete open files
+        drop(self.file.take());
+        if !self.path.as_os_str().is_empty() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    #[tokio::test]
+    async fn test_temp_file_is_deleted_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut first = TempFile::new_in(dir.path()).await.unwrap();
+        let second = TempFile::new_in(dir.path()).await.unwrap();
+        assert_ne!(first.path(), second.path());
+
+        first.write_all(b"data").await.unwrap();
+        first.rewind().await.unwrap();
+        let mut content = String::new();
+        first.read_to_string(&mut content).await.unwrap();
+        assert_eq!(content, "data");
+
+        drop(first);
+        drop(second);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_temp_file_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("dest.txt");
+        std::fs::write(&dest, b"old").unwrap();
+
+        let mut file = TempFile::new_in(dir.path()).await.unwrap();
+        file.write_all(b"new").await.unwrap();
+        file.persist(&dest).await.unwrap();
+
+        assert_eq!(std::fs::read(&dest).unwrap(), b"new");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
 }